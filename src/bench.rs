@@ -0,0 +1,135 @@
+use crate::page;
+use crate::uart;
+
+// number of rounds each benchmark is run for
+const ROUNDS: usize = 256;
+
+// read the machine cycle counter
+fn cycles() -> usize {
+    let c: usize;
+    unsafe {
+        asm!("csrr $0, mcycle" : "=r"(c) ::: "volatile");
+    }
+    c
+}
+
+// min/max/average over a set of cycle measurements
+struct Stats {
+    min: usize,
+    max: usize,
+    total: usize,
+    count: usize,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            min: usize::max_value(),
+            max: 0,
+            total: 0,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, c: usize) {
+        if c < self.min {
+            self.min = c;
+        }
+        if c > self.max {
+            self.max = c;
+        }
+        self.total += c;
+        self.count += 1;
+    }
+
+    fn print(&self, name: &str) {
+        println!(
+            "{:<16}: avg {:>8} min {:>8} max {:>8}",
+            name,
+            self.total / self.count,
+            self.min,
+            self.max
+        );
+    }
+}
+
+// time alloc and dealloc of a run of pages
+fn bench_page(pages: usize, alloc_name: &str, dealloc_name: &str) {
+    let mut alloc_stats = Stats::new();
+    let mut dealloc_stats = Stats::new();
+
+    for _ in 0..ROUNDS {
+        let start = cycles();
+        let p = page::alloc(pages);
+        alloc_stats.add(cycles() - start);
+        assert!(!p.is_null());
+
+        let start = cycles();
+        page::dealloc(p);
+        dealloc_stats.add(cycles() - start);
+    }
+
+    alloc_stats.print(alloc_name);
+    dealloc_stats.print(dealloc_name);
+}
+
+// time mapping a page into an empty table and unmapping it again, so
+// each round also allocates and reclaims the tables in between
+fn bench_map() {
+    let mut map_stats = Stats::new();
+    let mut unmap_stats = Stats::new();
+    let root = unsafe { (page::zalloc(1) as *mut page::Table).as_mut().unwrap() };
+    let p = page::alloc(1);
+    assert!(!p.is_null());
+    let vaddr = 0x4000_0000;
+
+    for _ in 0..ROUNDS {
+        let start = cycles();
+        page::map(root, vaddr, p as usize, page::EntryBits::RW.val(), 0);
+        map_stats.add(cycles() - start);
+
+        let start = cycles();
+        let unmapped = page::unmap_range(root, 0, vaddr, page::PAGE_SIZE, true);
+        unmap_stats.add(cycles() - start);
+        assert!(unmapped);
+    }
+
+    map_stats.print("map(1)");
+    unmap_stats.print("unmap_range(1)");
+    page::dealloc(p);
+    page::dealloc(root as *mut page::Table as *mut u8);
+}
+
+// time writing a block of bytes out the uart
+fn bench_uart() {
+    const BYTES: usize = 1024;
+    let mut uart = uart::log_port();
+
+    let start = cycles();
+    for _ in 0..BYTES {
+        uart.put(b'.');
+    }
+    let total = cycles() - start;
+    println!();
+
+    println!(
+        "{:<16}: total {:>8} per byte {:>8}",
+        "uart put (1 KiB)",
+        total,
+        total / BYTES
+    );
+}
+
+/// Run the kernel microbenchmarks and print the results in cycles.
+/// Expects page::init and the uart to have been set up already.
+pub fn run() {
+    println!();
+    println!("BENCHMARKS ({} rounds, in cycles)", ROUNDS);
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    bench_page(1, "page alloc(1)", "page dealloc(1)");
+    bench_page(64, "page alloc(64)", "page dealloc(64)");
+    bench_map();
+    bench_uart();
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    println!();
+}
@@ -0,0 +1,78 @@
+use core::ptr::null_mut;
+
+// EARLY BOOT BUMP ALLOCATOR
+// Before page::init builds the page table there is no allocator at all,
+// but early code (e.g. parsing the DTB to discover memory) can still need
+// scratch space. This hands out memory from a fixed arena at the top of
+// the heap, growing downwards, and never frees anything.
+//
+// [HEAP_START ... | ARENA: free ... <-- NEXT | used ] <-- HEAP_START + HEAP_SIZE
+//
+// Once page::init takes over, the pages that were handed out stay
+// reserved for good and the rest of the arena becomes ordinary free pages.
+
+extern "C" {
+    static HEAP_START: usize;
+    static HEAP_SIZE: usize;
+}
+
+pub const ARENA_SIZE: usize = 64 * 1024;
+
+// lowest address handed out so far, 0 until the first allocation
+static mut NEXT: usize = 0;
+// set once page::init has taken over the arena
+static mut RETIRED: bool = false;
+
+fn arena_top() -> usize {
+    unsafe { HEAP_START + HEAP_SIZE }
+}
+
+// allocate size bytes aligned to align (a power of two)
+// returns null if the arena is exhausted
+pub fn alloc(size: usize, align: usize) -> *mut u8 {
+    assert!(align.is_power_of_two());
+    unsafe {
+        assert!(!RETIRED, "Bump allocator used after page::init!");
+        let top = arena_top();
+        if NEXT == 0 {
+            NEXT = top;
+        }
+
+        let bottom = top - ARENA_SIZE;
+        if size > NEXT - bottom {
+            return null_mut();
+        }
+        let addr = (NEXT - size) & !(align - 1);
+        if addr < bottom {
+            return null_mut();
+        }
+
+        NEXT = addr;
+        addr as *mut u8
+    }
+}
+
+// allocate and zero
+pub fn zalloc(size: usize, align: usize) -> *mut u8 {
+    let ret = alloc(size, align);
+    if !ret.is_null() {
+        unsafe {
+            core::ptr::write_bytes(ret, 0, size);
+        }
+    }
+    ret
+}
+
+// stop handing out memory and return the lowest address in use,
+// which is the top of the arena if nothing was ever allocated
+// called by page::init when it takes over
+pub fn retire() -> usize {
+    unsafe {
+        RETIRED = true;
+        if NEXT == 0 {
+            arena_top()
+        } else {
+            NEXT
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use core::fmt::{Error, Write};
+use core::str;
+
+// FIXED-CAPACITY STRING
+// A UTF-8 string of up to N bytes stored in place, so text can be built
+// up (including with write!) without a heap.
+
+pub struct FixedString<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+// lets write!/format_args fill in a FixedString
+// fails once the string is out of room
+impl<const N: usize> Write for FixedString<N> {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.push_str(s).map_err(|_| Error)
+    }
+}
+
+impl<const N: usize> FixedString<N> {
+    pub fn new() -> Self {
+        FixedString { buf: [0; N], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever filled from &str and char, so always valid UTF-8
+        unsafe { str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // append the whole string, or nothing if it doesn't fit
+    pub fn push_str(&mut self, s: &str) -> Result<(), ()> {
+        let bytes = s.as_bytes();
+        if self.len + bytes.len() > N {
+            return Err(());
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    pub fn push(&mut self, c: char) -> Result<(), ()> {
+        let mut tmp = [0; 4];
+        self.push_str(c.encode_utf8(&mut tmp))
+    }
+
+    // remove the last char
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.as_str().chars().next_back()?;
+        self.len -= c.len_utf8();
+        Some(c)
+    }
+}
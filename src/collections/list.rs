@@ -0,0 +1,155 @@
+use core::ptr::null_mut;
+
+// INTRUSIVE DOUBLY-LINKED LIST
+// [List] head --> [Link] <--> [Link] <--> [Link] <-- tail
+//                    |           |           |
+//                 (struct)    (struct)    (struct)
+//
+// Each node embeds a Link inside the struct being listed, so pushing and
+// removing never allocates. The list only holds raw pointers to those
+// Links; a struct keeps its Link as the first field under #[repr(C)] so
+// a Link pointer can be cast back to the struct that owns it.
+
+pub struct Link {
+    prev: *mut Link,
+    next: *mut Link,
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Link {
+            prev: null_mut(),
+            next: null_mut(),
+        }
+    }
+}
+
+pub struct List {
+    head: *mut Link,
+    tail: *mut Link,
+    len: usize,
+}
+
+impl List {
+    // const so lists can live in statics
+    pub const fn new() -> Self {
+        List {
+            head: null_mut(),
+            tail: null_mut(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    pub fn front(&self) -> Option<*mut Link> {
+        if self.head.is_null() {
+            None
+        } else {
+            Some(self.head)
+        }
+    }
+
+    pub fn back(&self) -> Option<*mut Link> {
+        if self.tail.is_null() {
+            None
+        } else {
+            Some(self.tail)
+        }
+    }
+
+    // link must stay valid and not be moved while it's on the list,
+    // and can only be on one list at a time
+    pub unsafe fn push_front(&mut self, link: *mut Link) {
+        (*link).prev = null_mut();
+        (*link).next = self.head;
+        if self.head.is_null() {
+            self.tail = link;
+        } else {
+            (*self.head).prev = link;
+        }
+        self.head = link;
+        self.len += 1;
+    }
+
+    // same requirements as push_front
+    pub unsafe fn push_back(&mut self, link: *mut Link) {
+        (*link).next = null_mut();
+        (*link).prev = self.tail;
+        if self.tail.is_null() {
+            self.head = link;
+        } else {
+            (*self.tail).next = link;
+        }
+        self.tail = link;
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<*mut Link> {
+        let link = self.front()?;
+        unsafe {
+            self.remove(link);
+        }
+        Some(link)
+    }
+
+    pub fn pop_back(&mut self) -> Option<*mut Link> {
+        let link = self.back()?;
+        unsafe {
+            self.remove(link);
+        }
+        Some(link)
+    }
+
+    // link must currently be on this list
+    pub unsafe fn remove(&mut self, link: *mut Link) {
+        let prev = (*link).prev;
+        let next = (*link).next;
+
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+
+        if next.is_null() {
+            self.tail = prev;
+        } else {
+            (*next).prev = prev;
+        }
+
+        (*link).prev = null_mut();
+        (*link).next = null_mut();
+        self.len -= 1;
+    }
+
+    // walk the list front to back
+    // the list must not be changed while iterating
+    pub fn iter(&self) -> Iter {
+        Iter { cur: self.head }
+    }
+}
+
+pub struct Iter {
+    cur: *mut Link,
+}
+
+impl Iterator for Iter {
+    type Item = *mut Link;
+
+    fn next(&mut self) -> Option<*mut Link> {
+        if self.cur.is_null() {
+            None
+        } else {
+            let link = self.cur;
+            self.cur = unsafe { (*link).next };
+            Some(link)
+        }
+    }
+}
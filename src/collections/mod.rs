@@ -0,0 +1,8 @@
+// Collections that work without a heap
+// These are usable from the allocators themselves and from hot paths
+// where calling into kmem isn't wanted
+
+pub mod fixed_string;
+pub mod list;
+pub mod range_tree;
+pub mod ring_buffer;
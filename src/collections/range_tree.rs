@@ -0,0 +1,222 @@
+use core::ptr::null_mut;
+
+// INTRUSIVE RANGE TREE
+// An AVL tree of non-overlapping [start, end) address ranges. Like the
+// intrusive list, each node lives inside the struct it describes, so
+// insert/remove never allocate and lookups are O(log n).
+//
+// Since ranges never overlap, ordering nodes by start also orders them
+// by end, which is what lets find() descend a plain BST.
+
+pub struct RangeNode {
+    pub start: usize,
+    pub end: usize,
+    left: *mut RangeNode,
+    right: *mut RangeNode,
+    height: isize,
+}
+
+impl RangeNode {
+    pub const fn new(start: usize, end: usize) -> Self {
+        RangeNode {
+            start,
+            end,
+            left: null_mut(),
+            right: null_mut(),
+            height: 1,
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+pub struct RangeTree {
+    root: *mut RangeNode,
+    len: usize,
+}
+
+impl RangeTree {
+    // const so trees can live in statics
+    pub const fn new() -> Self {
+        RangeTree {
+            root: null_mut(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_null()
+    }
+
+    // add a node, returns false (and leaves the tree alone) if its range
+    // overlaps one already in the tree
+    // node must stay valid and not be moved while it's in the tree
+    pub unsafe fn insert(&mut self, node: *mut RangeNode) -> bool {
+        assert!((*node).start < (*node).end);
+        if self.find_overlap((*node).start, (*node).end).is_some() {
+            return false;
+        }
+        (*node).left = null_mut();
+        (*node).right = null_mut();
+        (*node).height = 1;
+        self.root = insert(self.root, node);
+        self.len += 1;
+        true
+    }
+
+    // node must currently be in this tree
+    pub unsafe fn remove(&mut self, node: *mut RangeNode) {
+        self.root = remove(self.root, (*node).start);
+        (*node).left = null_mut();
+        (*node).right = null_mut();
+        (*node).height = 1;
+        self.len -= 1;
+    }
+
+    // find the node whose range contains addr
+    pub fn find(&self, addr: usize) -> Option<*mut RangeNode> {
+        self.find_overlap(addr, addr.saturating_add(1))
+    }
+
+    // find any node overlapping [start, end)
+    pub fn find_overlap(&self, start: usize, end: usize) -> Option<*mut RangeNode> {
+        let mut n = self.root;
+        unsafe {
+            while !n.is_null() {
+                if (*n).end <= start {
+                    n = (*n).right;
+                } else if (*n).start >= end {
+                    n = (*n).left;
+                } else {
+                    return Some(n);
+                }
+            }
+        }
+        None
+    }
+
+    // visit every node in address order
+    // the tree must not be changed from inside f
+    pub fn for_each<F: FnMut(*mut RangeNode)>(&self, mut f: F) {
+        unsafe {
+            walk(self.root, &mut f);
+        }
+    }
+}
+
+unsafe fn walk<F: FnMut(*mut RangeNode)>(n: *mut RangeNode, f: &mut F) {
+    if n.is_null() {
+        return;
+    }
+    walk((*n).left, f);
+    f(n);
+    walk((*n).right, f);
+}
+
+// AVL helpers
+// each returns the new root of the subtree it was given
+
+unsafe fn height(n: *mut RangeNode) -> isize {
+    if n.is_null() {
+        0
+    } else {
+        (*n).height
+    }
+}
+
+unsafe fn update(n: *mut RangeNode) {
+    let l = height((*n).left);
+    let r = height((*n).right);
+    (*n).height = 1 + if l > r { l } else { r };
+}
+
+unsafe fn rotate_right(n: *mut RangeNode) -> *mut RangeNode {
+    let l = (*n).left;
+    (*n).left = (*l).right;
+    (*l).right = n;
+    update(n);
+    update(l);
+    l
+}
+
+unsafe fn rotate_left(n: *mut RangeNode) -> *mut RangeNode {
+    let r = (*n).right;
+    (*n).right = (*r).left;
+    (*r).left = n;
+    update(n);
+    update(r);
+    r
+}
+
+unsafe fn balance(n: *mut RangeNode) -> *mut RangeNode {
+    update(n);
+    let bf = height((*n).left) - height((*n).right);
+    if bf > 1 {
+        // left heavy, straighten a left-right case first
+        if height((*(*n).left).left) < height((*(*n).left).right) {
+            (*n).left = rotate_left((*n).left);
+        }
+        return rotate_right(n);
+    }
+    if bf < -1 {
+        // right heavy, straighten a right-left case first
+        if height((*(*n).right).right) < height((*(*n).right).left) {
+            (*n).right = rotate_right((*n).right);
+        }
+        return rotate_left(n);
+    }
+    n
+}
+
+unsafe fn insert(n: *mut RangeNode, node: *mut RangeNode) -> *mut RangeNode {
+    if n.is_null() {
+        return node;
+    }
+    if (*node).start < (*n).start {
+        (*n).left = insert((*n).left, node);
+    } else {
+        (*n).right = insert((*n).right, node);
+    }
+    balance(n)
+}
+
+// unlink the leftmost node of a subtree
+// returns (new subtree root, unlinked node)
+unsafe fn remove_min(n: *mut RangeNode) -> (*mut RangeNode, *mut RangeNode) {
+    if (*n).left.is_null() {
+        return ((*n).right, n);
+    }
+    let (left, min) = remove_min((*n).left);
+    (*n).left = left;
+    (balance(n), min)
+}
+
+unsafe fn remove(n: *mut RangeNode, start: usize) -> *mut RangeNode {
+    assert!(!n.is_null(), "Range not in tree!");
+    if start < (*n).start {
+        (*n).left = remove((*n).left, start);
+    } else if start > (*n).start {
+        (*n).right = remove((*n).right, start);
+    } else {
+        let left = (*n).left;
+        let right = (*n).right;
+        if left.is_null() {
+            return right;
+        }
+        if right.is_null() {
+            return left;
+        }
+        // two children, the next node up takes this one's place
+        let (right, min) = remove_min(right);
+        (*min).left = left;
+        (*min).right = right;
+        return balance(min);
+    }
+    balance(n)
+}
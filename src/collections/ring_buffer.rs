@@ -0,0 +1,98 @@
+use core::mem::MaybeUninit;
+
+// FIXED-CAPACITY RING BUFFER
+// [ . . 3 4 5 6 . . ]
+//       ^ head  ^ head + len
+//
+// Holds up to N items in place, wrapping around the end of the array.
+// Items go in at the back and normally come out at the front, but can
+// also be taken back off the back (used for line editing).
+
+pub struct RingBuffer<T: Copy, const N: usize> {
+    buf: [MaybeUninit<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub fn new() -> Self {
+        RingBuffer {
+            buf: [MaybeUninit::uninit(); N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    // index into buf of the i'th item from the front
+    fn slot(&self, i: usize) -> usize {
+        (self.head + i) % N
+    }
+
+    // add an item at the back, handing it back if there's no room
+    pub fn push_back(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        let i = self.slot(self.len);
+        self.buf[i] = MaybeUninit::new(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    // add an item at the back, dropping the oldest item if full
+    // (for logs where the newest entries matter most)
+    pub fn force_push(&mut self, item: T) {
+        if self.is_full() {
+            self.pop_front();
+        }
+        let _ = self.push_back(item);
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = unsafe { self.buf[self.head].assume_init() };
+        self.head = self.slot(1);
+        self.len -= 1;
+        Some(item)
+    }
+
+    pub fn pop_back(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { self.buf[self.slot(self.len)].assume_init() })
+    }
+
+    // look at the i'th item from the front without removing it
+    pub fn get(&self, i: usize) -> Option<T> {
+        if i >= self.len {
+            None
+        } else {
+            Some(unsafe { self.buf[self.slot(i)].assume_init() })
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// KEYMAP
+// Turns key events into characters. Key codes are the Linux input
+// (evdev) codes that virtio-input reports; PS/2 scancode set 1 make
+// codes are the same numbers for the main block of keys, so those can
+// be fed in too.
+//
+// Only keys that produce a single byte are mapped. Arrows, function
+// keys, and the keypad give nothing for now.
+
+// key codes with special meaning
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_RIGHTALT: u16 = 100;
+
+// key event values as sent by virtio-input
+pub const KEY_RELEASE: u32 = 0;
+pub const KEY_PRESS: u32 = 1;
+pub const KEY_REPEAT: u32 = 2;
+
+// number of key codes the layout tables cover (ESC = 1 to SPACE = 57)
+const KEYS: usize = 58;
+
+// characters produced per key code, 0 for keys that produce nothing
+// ENTER gives a carriage return and BACKSPACE gives DEL, like a terminal
+pub struct Layout {
+    pub name: &'static str,
+    pub normal: [u8; KEYS],
+    pub shifted: [u8; KEYS],
+}
+
+pub static US: Layout = Layout {
+    name: "us",
+    normal: *b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ",
+    shifted: *b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ",
+};
+
+pub static DVORAK: Layout = Layout {
+    name: "dvorak",
+    normal: *b"\0\x1b1234567890[]\x7f\t',.pyfgcrl/=\r\0aoeuidhtns-`\0\\;qjkxbmwvz\0*\0 ",
+    shifted: *b"\0\x1b!@#$%^&*(){}\x7f\t\"<>PYFGCRL?+\r\0AOEUIDHTNS_~\0|:QJKXBMWVZ\0*\0 ",
+};
+
+pub struct Keyboard {
+    layout: &'static Layout,
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    caps_lock: bool,
+}
+
+impl Keyboard {
+    pub const fn new(layout: &'static Layout) -> Self {
+        Keyboard {
+            layout,
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            caps_lock: false,
+        }
+    }
+
+    pub fn set_layout(&mut self, layout: &'static Layout) {
+        self.layout = layout;
+    }
+
+    pub fn layout(&self) -> &'static Layout {
+        self.layout
+    }
+
+    pub fn alt(&self) -> bool {
+        self.alt
+    }
+
+    // Handle a key event (code plus KEY_RELEASE/KEY_PRESS/KEY_REPEAT),
+    // returning the character it types if any.
+    pub fn key_event(&mut self, code: u16, value: u32) -> Option<u8> {
+        let pressed = value != KEY_RELEASE;
+
+        // modifiers
+        match code {
+            KEY_LEFTSHIFT => self.left_shift = pressed,
+            KEY_RIGHTSHIFT => self.right_shift = pressed,
+            KEY_LEFTCTRL => self.left_ctrl = pressed,
+            KEY_RIGHTCTRL => self.right_ctrl = pressed,
+            KEY_LEFTALT | KEY_RIGHTALT => self.alt = pressed,
+            // caps lock toggles on press, not on repeat or release
+            KEY_CAPSLOCK if value == KEY_PRESS => self.caps_lock = !self.caps_lock,
+            _ => {}
+        }
+
+        if !pressed || code as usize >= KEYS {
+            return None;
+        }
+
+        let normal = self.layout.normal[code as usize];
+        if normal == 0 {
+            return None;
+        }
+
+        // caps lock only flips letters
+        let mut shift = self.left_shift || self.right_shift;
+        if self.caps_lock && normal.is_ascii_alphabetic() {
+            shift = !shift;
+        }
+        let c = if shift {
+            self.layout.shifted[code as usize]
+        } else {
+            normal
+        };
+
+        // ctrl+letter (and ctrl+@[\]^_) give the matching control character
+        if self.left_ctrl || self.right_ctrl {
+            let upper = c.to_ascii_uppercase();
+            if upper >= b'@' && upper <= b'_' {
+                return Some(upper & 0x1f);
+            }
+        }
+        Some(c)
+    }
+
+    // Handle a PS/2 scancode set 1 byte: make codes are key codes,
+    // break codes are the same with bit 7 set
+    // (the 0xe0 extended prefix is ignored, so right ctrl/alt read as left)
+    pub fn scancode(&mut self, sc: u8) -> Option<u8> {
+        if sc == 0xe0 {
+            return None;
+        }
+        if sc & 0x80 != 0 {
+            self.key_event((sc & 0x7f) as u16, KEY_RELEASE)
+        } else {
+            self.key_event(sc as u16, KEY_PRESS)
+        }
+    }
+}
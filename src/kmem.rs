@@ -0,0 +1,251 @@
+use crate::collections::list::{Link, List};
+use crate::page::{self, Tag, PAGE_SIZE};
+use core::alloc::{GlobalAlloc, Layout};
+use core::{mem::size_of, ptr::null_mut};
+
+// KERNEL HEAP (kmem)
+// Serves allocations smaller than a page out of a pool of pages taken
+// from the page allocator once at boot.
+//
+// [KMEM POOL]
+// +--> [hdr | payload .............. | ftr]  <-- taken block
+// +--> [hdr | Link | (free) ........ | ftr]  <-- free block, on FREE_LIST
+// +--> [hdr | payload ... | ftr]
+// ...
+//
+// hdr: block size in bytes (including hdr and ftr) | taken bit
+// ftr: block size again, so kfree can find the start of the block
+//      before it and merge with it if it's free
+// Free blocks keep a Link in their payload, threading them onto an
+// intrusive free list that kmalloc searches first fit.
+
+// pages given to the kernel heap, 2 MiB
+const KMEM_PAGES: usize = 512;
+
+// all block sizes and payloads are 8-byte aligned
+const ALIGN_ORDER: usize = 3;
+const HDR: usize = size_of::<usize>();
+const FTR: usize = size_of::<usize>();
+// a free block has to be big enough to hold its Link
+const MIN_BLOCK: usize = HDR + size_of::<Link>() + FTR;
+// lowest bit of the header, sizes are always multiples of 8
+const TAKEN: usize = 1;
+
+static mut KMEM_START: usize = 0;
+static mut KMEM_END: usize = 0;
+static mut FREE_LIST: List = List::new();
+// bytes currently handed out, counting headers and footers
+static mut KMEM_USED: usize = 0;
+
+unsafe fn block_size(block: usize) -> usize {
+    *(block as *const usize) & !TAKEN
+}
+
+unsafe fn block_is_taken(block: usize) -> bool {
+    *(block as *const usize) & TAKEN != 0
+}
+
+// write header and footer of a block
+unsafe fn set_block(block: usize, size: usize, taken: bool) {
+    *(block as *mut usize) = size | if taken { TAKEN } else { 0 };
+    *((block + size - FTR) as *mut usize) = size;
+}
+
+unsafe fn block_link(block: usize) -> *mut Link {
+    (block + HDR) as *mut Link
+}
+
+unsafe fn link_block(link: *mut Link) -> usize {
+    link as usize - HDR
+}
+
+// mark a block free and put it on the free list
+unsafe fn free_block(block: usize, size: usize) {
+    set_block(block, size, false);
+    let link = block_link(block);
+    *link = Link::new();
+    FREE_LIST.push_front(link);
+}
+
+// initialize the kernel heap, must be called after page::init
+pub fn init() {
+    unsafe {
+        let pool = page::alloc_tagged(KMEM_PAGES, Tag::Kernel);
+        assert!(!pool.is_null(), "No pages for the kernel heap!");
+        KMEM_START = pool as usize;
+        KMEM_END = KMEM_START + KMEM_PAGES * PAGE_SIZE;
+        // the whole pool starts out as one free block
+        free_block(KMEM_START, KMEM_END - KMEM_START);
+    }
+}
+
+// allocate sz bytes, returns null if the heap is out of room
+pub fn kmalloc(sz: usize) -> *mut u8 {
+    if sz == 0 {
+        return null_mut();
+    }
+    // a size near usize::MAX would overflow once the header, footer,
+    // and rounding are added, and could never fit anyway
+    let mut need = match sz.checked_add(HDR + FTR + (1 << ALIGN_ORDER) - 1) {
+        Some(_) => page::align_val(sz + HDR + FTR, ALIGN_ORDER),
+        None => return null_mut(),
+    };
+    if need < MIN_BLOCK {
+        need = MIN_BLOCK;
+    }
+
+    unsafe {
+        // first fit
+        let found = FREE_LIST.iter().find(|&link| block_size(link_block(link)) >= need);
+        let link = match found {
+            Some(link) => link,
+            None => return null_mut(),
+        };
+        FREE_LIST.remove(link);
+
+        let block = link_block(link);
+        let size = block_size(block);
+        if size - need >= MIN_BLOCK {
+            // split off the rest as a new free block
+            free_block(block + need, size - need);
+            set_block(block, need, true);
+        } else {
+            // too small a remainder to track, hand out the whole block
+            set_block(block, size, true);
+        }
+        KMEM_USED += block_size(block);
+        (block + HDR) as *mut u8
+    }
+}
+
+// allocate and zero sz bytes
+pub fn kzmalloc(sz: usize) -> *mut u8 {
+    let ret = kmalloc(sz);
+    if !ret.is_null() {
+        unsafe {
+            core::ptr::write_bytes(ret, 0, sz);
+        }
+    }
+    ret
+}
+
+// free memory from kmalloc, merging it with free neighbors
+pub fn kfree(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        let mut block = ptr as usize - HDR;
+        assert!(block >= KMEM_START && block < KMEM_END, "Not a kmem pointer!");
+        assert!(block_is_taken(block), "Possible double-free!");
+        let mut size = block_size(block);
+        KMEM_USED -= size;
+
+        // merge with the block after this one
+        let next = block + size;
+        if next < KMEM_END && !block_is_taken(next) {
+            FREE_LIST.remove(block_link(next));
+            size += block_size(next);
+        }
+
+        // merge with the block before this one, found through its footer
+        if block > KMEM_START {
+            let prev_size = *((block - FTR) as *const usize);
+            let prev = block - prev_size;
+            if !block_is_taken(prev) {
+                FREE_LIST.remove(block_link(prev));
+                block = prev;
+                size += prev_size;
+            }
+        }
+
+        free_block(block, size);
+    }
+}
+
+// bytes currently allocated, including block headers and footers
+pub fn used() -> usize {
+    unsafe { KMEM_USED }
+}
+
+/// Print every block in the kernel heap
+/// This is mainly used for debugging.
+pub fn print_table() {
+    unsafe {
+        println!();
+        println!(
+            "KMEM TABLE\nPOOL: 0x{:x} -> 0x{:x}",
+            KMEM_START, KMEM_END
+        );
+        println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+        let mut block = KMEM_START;
+        while block < KMEM_END {
+            let size = block_size(block);
+            println!(
+                "0x{:x}: {:>9} bytes {}",
+                block,
+                size,
+                if block_is_taken(block) { "taken" } else { "free" }
+            );
+            block += size;
+        }
+        println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+        println!(
+            "Used: {} bytes, free: {} bytes in {} block(s).",
+            KMEM_USED,
+            KMEM_END - KMEM_START - KMEM_USED,
+            FREE_LIST.len()
+        );
+        println!();
+    }
+}
+
+// ==========================================================================================================
+// GlobalAlloc, so the alloc crate (Box, Vec, String, ...) works in the kernel
+// ==========================================================================================================
+
+struct OsGlobalAlloc;
+
+impl OsGlobalAlloc {
+    // kmalloc only guarantees 8-byte alignment and its pool is shared by
+    // every small allocation, so anything big or more aligned gets whole pages
+    fn use_pages(layout: &Layout) -> bool {
+        layout.size() >= PAGE_SIZE || layout.align() > 1 << ALIGN_ORDER
+    }
+}
+
+unsafe impl GlobalAlloc for OsGlobalAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if Self::use_pages(&layout) {
+            // pages are only ever page aligned
+            if layout.align() > PAGE_SIZE {
+                return null_mut();
+            }
+            // zero-sized but over-aligned still needs somewhere to point
+            let pages = page::align_val(layout.size(), 12) / PAGE_SIZE;
+            page::alloc(core::cmp::max(pages, 1))
+        } else {
+            kmalloc(layout.size())
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if Self::use_pages(&layout) {
+            page::dealloc(ptr);
+        } else {
+            kfree(ptr);
+        }
+    }
+}
+
+#[global_allocator]
+static GA: OsGlobalAlloc = OsGlobalAlloc;
+
+#[alloc_error_handler]
+pub fn alloc_error(l: Layout) -> ! {
+    panic!(
+        "Allocator failed to allocate {} bytes with {}-byte alignment.",
+        l.size(),
+        l.align()
+    );
+}
@@ -1,10 +1,9 @@
 #![no_std] // don't load the standard library for rust
-#![feature(panic_info_message, asm)] // enable inline assembly and panic info
+#![feature(panic_info_message, asm, const_generics, alloc_error_handler)] // enable inline assembly, panic info, const generics, and an allocation failure handler
+#![allow(incomplete_features)] // const_generics is still marked incomplete
 
-const BACKSPACE: u8 = b'\x08';
-const NEWLINE: u8 = b'\x0a';
-const CARR_RET: u8 = b'\x0d';
-const ESCAPE: u8 = b'\x1b';
+// heap types (Box, Vec, String, ...), backed by kmem's global allocator
+extern crate alloc;
 
 /*
 +-----------+
@@ -17,7 +16,7 @@ const ESCAPE: u8 = b'\x1b';
 macro_rules! print {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        let _ = write!(crate::uart::Uart::new(0x1000_0000), $($args)+);
+        let _ = write!(crate::uart::log_port(), $($args)+);
     });
 }
 
@@ -37,6 +36,16 @@ macro_rules! println
     });
 }
 
+// record a trace event, see trace.rs
+// compiles to nothing unless built with the trace feature
+#[macro_export]
+macro_rules! trace {
+    ($event:ident, $a0:expr, $a1:expr) => ({
+        #[cfg(feature = "trace")]
+        crate::trace::record(crate::trace::Event::$event, $a0 as u64, $a1 as u32);
+    });
+}
+
 /*
 +-------------------------------+
 |LANGUAGE STRUCTURES / FUNCTIONS|
@@ -90,12 +99,25 @@ pub fn id_map_range(root: &mut page::Table, start: usize, end: usize, bits: i64)
 +-----------+
 */
 
+// run driver self tests at boot
+const POST: bool = false;
+
 #[no_mangle]
 extern "C" fn kmain() {
-    let mut my_uart = uart::Uart::new(0x1000_0000);
+    let mut my_uart = uart::Uart::new(uart::CONSOLE_BASE);
     my_uart.init();
 
+    if POST {
+        if uart::selftest() {
+            println!("POST: uart loopback ok");
+        } else {
+            println!("POST: uart loopback FAILED");
+        }
+    }
+
     page::init();
+    page::asid_init();
+    kmem::init();
 
     for _ in 0..64 {
         page::alloc(1);
@@ -105,56 +127,20 @@ extern "C" fn kmain() {
 
     page::print_page_allocations();
 
-    // TODO: stopped at end of ch3.2 because no kmem implementation
-
 
     // println!("This is my operating system!");
     // println!("I'm so awesome. If you start typing something, I'll show you what you typed!");
 
+    // let mut console = tty::Tty::new(uart::CONSOLE_BASE);
+    // let mut line = [0u8; 128];
     // loop {
-    //     if let Some(c) = my_uart.get() {
-    //         match c as u8 {
-    //             BACKSPACE => {
-    //                 // for backspace need to move back 1 char, then overwrite
-    //                 // char at point with space, then move back again
-    //                 print!("{}{}{}", 8 as char, ' ', 8 as char);
-    //             }
-    //             NEWLINE | CARR_RET => {
-    //                 // newline or carriage return
-    //                 println!();
-    //             }
-    //             // escape char for escape sequence
-    //             ESCAPE => {
-    //                 if let Some(next_byte) = my_uart.get() {
-    //                     // [ for start of sequence
-    //                     if next_byte == 91 {
-    //                         if let Some(b) = my_uart.get() {
-    //                             match b as char {
-    //                                 'A' => {
-    //                                     println!("Up");
-    //                                 }
-    //                                 'B' => {
-    //                                     println!("Down");
-    //                                 }
-    //                                 'C' => {
-    //                                     println!("Right");
-    //                                 }
-    //                                 'D' => {
-    //                                     println!("Left");
-    //                                 }
-    //                                 _ => {
-    //                                     println!("Invalid");
-    //                                 }
-    //                             }
-    //                         }
-    //                     }
-    //                 }
-    //             }
-    //             _ => {
-    //                 print!("{}", c as char);
-    //             }
-    //         }
-    //     }
+    //     // nothing else to do until a line comes in
+    //     page::refill_zero_pool();
+    //     // the tty handles echo, backspace, and newlines for us
+    //     // and hands back a whole line once enter is pressed
+    //     let n = console.read(&mut line);
+    //     console.write(b"You typed: ");
+    //     console.write(&line[..n]);
     // }
 }
 
@@ -185,5 +171,16 @@ extern "C" fn kmain() {
 +------------+
 */
 
+pub mod bench;
+pub mod bump;
+pub mod checksum;
+pub mod collections;
+pub mod keymap;
+pub mod kmem;
+pub mod lz4;
 pub mod page;
+pub mod slab;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod tty;
 pub mod uart;
@@ -0,0 +1,185 @@
+// LZ4 DECOMPRESSION
+// Handles raw LZ4 blocks and the LZ4 frame format (what `lz4` on the
+// host produces), so compressed images can be detected and unpacked
+// in place without a heap.
+//
+// A block is a series of sequences:
+// [token] [literal length+] [literals] [offset lo, hi] [match length+]
+//  high nibble of token = literal length, low nibble = match length - 4,
+//  a nibble of 15 means more length bytes follow (until one isn't 255)
+// The last sequence of a block has only literals.
+
+// frame magic number, stored little-endian
+const FRAME_MAGIC: u32 = 0x184d_2204;
+// size of a block header and of the optional checksums
+const WORD: usize = 4;
+// the high bit of a block size marks an uncompressed block
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+
+// frame descriptor FLG bits
+const FLG_VERSION_MASK: u8 = 0b1100_0000;
+const FLG_VERSION: u8 = 0b0100_0000;
+const FLG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLG_CONTENT_SIZE: u8 = 1 << 3;
+const FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLG_DICT_ID: u8 = 1 << 0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Lz4Error {
+    // input ended in the middle of a sequence or frame
+    Truncated,
+    // output buffer is too small
+    OutputFull,
+    // a match points before the start of the output
+    BadOffset,
+    // not an LZ4 frame, or a version we don't know
+    BadMagic,
+    // frame uses a preset dictionary
+    Unsupported,
+}
+
+// check for the LZ4 frame magic number
+pub fn is_lz4(data: &[u8]) -> bool {
+    data.len() >= WORD && read_u32(data, 0) == FRAME_MAGIC
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+// decompress a single raw block into dst, returning the number of bytes written
+pub fn decompress_block(src: &[u8], dst: &mut [u8]) -> Result<usize, Lz4Error> {
+    decode_block(src, dst, 0)
+}
+
+// decompress a whole LZ4 frame into dst, returning the number of bytes written
+// block and content checksums are skipped, not verified
+pub fn decompress_frame(src: &[u8], dst: &mut [u8]) -> Result<usize, Lz4Error> {
+    if !is_lz4(src) {
+        return Err(Lz4Error::BadMagic);
+    }
+    // magic, FLG, BD
+    if src.len() < WORD + 2 {
+        return Err(Lz4Error::Truncated);
+    }
+    let flg = src[WORD];
+    if flg & FLG_VERSION_MASK != FLG_VERSION {
+        return Err(Lz4Error::BadMagic);
+    }
+    if flg & FLG_DICT_ID != 0 {
+        return Err(Lz4Error::Unsupported);
+    }
+
+    // skip magic, FLG, BD, optional content size, and the header checksum
+    let mut pos = WORD + 2;
+    if flg & FLG_CONTENT_SIZE != 0 {
+        pos += 8;
+    }
+    pos += 1;
+
+    let mut out = 0;
+    loop {
+        if pos + WORD > src.len() {
+            return Err(Lz4Error::Truncated);
+        }
+        let header = read_u32(src, pos);
+        pos += WORD;
+
+        // a zero size is the end mark
+        if header == 0 {
+            break;
+        }
+
+        let size = (header & !BLOCK_UNCOMPRESSED) as usize;
+        if pos + size > src.len() {
+            return Err(Lz4Error::Truncated);
+        }
+        let block = &src[pos..pos + size];
+
+        if header & BLOCK_UNCOMPRESSED != 0 {
+            if out + size > dst.len() {
+                return Err(Lz4Error::OutputFull);
+            }
+            dst[out..out + size].copy_from_slice(block);
+            out += size;
+        } else {
+            // blocks may be linked, so matches are allowed to reach
+            // back into earlier blocks' output
+            out = decode_block(block, dst, out)?;
+        }
+
+        pos += size;
+        if flg & FLG_BLOCK_CHECKSUM != 0 {
+            pos += WORD;
+        }
+    }
+
+    if flg & FLG_CONTENT_CHECKSUM != 0 && pos + WORD > src.len() {
+        return Err(Lz4Error::Truncated);
+    }
+    Ok(out)
+}
+
+// read an extended length: keep adding bytes until one isn't 255
+fn read_length(src: &[u8], pos: &mut usize, mut len: usize) -> Result<usize, Lz4Error> {
+    if len != 15 {
+        return Ok(len);
+    }
+    loop {
+        let b = *src.get(*pos).ok_or(Lz4Error::Truncated)?;
+        *pos += 1;
+        len += b as usize;
+        if b != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+// decode a block into dst starting at dst[out], matches may reference
+// anything already in dst[..out]
+// returns the new end of the output
+fn decode_block(src: &[u8], dst: &mut [u8], mut out: usize) -> Result<usize, Lz4Error> {
+    let mut pos = 0;
+    while pos < src.len() {
+        let token = src[pos];
+        pos += 1;
+
+        // literals
+        let lit_len = read_length(src, &mut pos, (token >> 4) as usize)?;
+        if pos + lit_len > src.len() {
+            return Err(Lz4Error::Truncated);
+        }
+        if out + lit_len > dst.len() {
+            return Err(Lz4Error::OutputFull);
+        }
+        dst[out..out + lit_len].copy_from_slice(&src[pos..pos + lit_len]);
+        pos += lit_len;
+        out += lit_len;
+
+        // the last sequence ends after its literals
+        if pos == src.len() {
+            break;
+        }
+
+        // match
+        if pos + 2 > src.len() {
+            return Err(Lz4Error::Truncated);
+        }
+        let offset = u16::from_le_bytes([src[pos], src[pos + 1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out {
+            return Err(Lz4Error::BadOffset);
+        }
+        let match_len = read_length(src, &mut pos, (token & 0xf) as usize)? + 4;
+        if out + match_len > dst.len() {
+            return Err(Lz4Error::OutputFull);
+        }
+        // copy a byte at a time since the match may overlap what it's writing
+        let from = out - offset;
+        for i in 0..match_len {
+            dst[out + i] = dst[from + i];
+        }
+        out += match_len;
+    }
+    Ok(out)
+}
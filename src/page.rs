@@ -1,14 +1,24 @@
-use core::{mem::size_of, ptr::null_mut};
+use crate::bump;
+use crate::collections::list::{Link, List};
+use crate::collections::range_tree::{RangeNode, RangeTree};
+use crate::slab::{SlabCache, SlabObject};
+use core::{cmp::min, mem::size_of, ptr::null_mut};
 
 // MEMORY LAYOUT
 // [PAGE TABLE]
-// +--> Page table 1 bits {Empty, Taken, Last}
-// +--> Page table 2 bits {Empty, Taken, Last}
-// +--> Page table 3 bits {Empty, Taken, Last}
+// +--> Page table 1 bits {Empty, Taken, Last, Head}
+// +--> Page table 2 bits {Empty, Taken, Last, Head}
+// +--> Page table 3 bits {Empty, Taken, Last, Head}
 // ...
-// [FREE PAGE 1] <-- ALLOC_START
-// [FREE PAGE 2] <-- (ALLOC_START + 1 * PAGE_SIZE)
-// [FREE PAGE 3] <-- (ALLOC_START + 2 * PAGE_SIZE)
+// [REFCOUNTS] <-- REFS, one u16 per page
+// [HEADS] <-- HEADS, one u32 per page
+// ...
+// [CMA PAGE 1] <-- ALLOC_START
+// ...
+// [CMA PAGE CMA_PAGES]
+// [FREE PAGE 1] <-- (ALLOC_START + CMA_PAGES * PAGE_SIZE)
+// [FREE PAGE 2] <-- (ALLOC_START + (CMA_PAGES + 1) * PAGE_SIZE)
+// [FREE PAGE 3] <-- (ALLOC_START + (CMA_PAGES + 2) * PAGE_SIZE)
 // ...
 // [MEMORY_END]
 
@@ -25,23 +35,52 @@ static mut ALLOC_START: usize = 0;
 const PAGE_ORDER: usize = 12;
 pub const PAGE_SIZE: usize = 1 << 12; // 4096-byte pages
 
+// number of pages at the start of the allocatable area held back for
+// the contiguous memory allocator (CMA), so large physically contiguous
+// buffers can still be found after the rest of the heap fragments
+pub const CMA_PAGES: usize = 1024; // 4 MiB
+
 // align value to a given order
 pub const fn align_val(val: usize, order: usize) -> usize {
     let o = (1usize << order) - 1;
     (val + o) & !o
 }
 
-// Could use a linked list structure for tracking memory,
-// but instead we're tracking using indexing to save memory
-// struct FreePages {
-//     struct FreePages *next;
-// };
+// BUDDY ALLOCATOR
+// Free memory is kept as blocks of 2^order pages, one free list per order.
+// [order 0] --> [1 page] <--> [1 page]
+// [order 1] --> [2 pages]
+// [order 2] --> [4 pages] <--> [4 pages] <--> [4 pages]
+// ...
+// An allocation takes the smallest block that fits, splitting bigger
+// blocks in half as needed, and gives the unused tail of the block back.
+// Freeing merges a block with its buddy (the other half of the block it
+// was split from) for as long as the buddy is free too.
+// The Link for a free block's list lives at the start of its first page,
+// so the free lists cost no memory of their own.
+
+// 2^15 pages = 128 MiB, the largest block the free lists track
+// an allocation no single block can hold (too big, or spread over
+// neighbouring blocks) falls back to a scan for a long enough run,
+// see Zone::take_run
+const MAX_ORDER: usize = 15;
+
+// single pages are what gets allocated most (page tables), so freed ones
+// are kept on a hot list up to this many and handed straight back out
+// without splitting or merging, the list is given back to the buddy
+// lists when a bigger allocation comes up short
+const HOT_PAGES: usize = 64;
+
+// zalloc(1) is on the hot path of map(), so this many pages are zeroed
+// ahead of time by refill_zero_pool, and given back like the hot list
+const ZERO_POOL_PAGES: usize = 32;
 
 #[repr(u8)]
 pub enum PageBits {
     Empty = 0,
     Taken = 1 << 0, // page taken?
     Last = 1 << 1,  // last page in contiguous allocation?
+    Head = 1 << 2,  // first page of a free block?
 }
 
 impl PageBits {
@@ -50,6 +89,55 @@ impl PageBits {
     }
 }
 
+// subsystem an allocation is charged to, kept in the upper bits of the
+// first page's flags so meminfo can show who is using memory
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum Tag {
+    Kernel = 0,
+    PageTable = 1,
+    Sched = 2,
+    Fs = 3,
+    Net = 4,
+    Driver = 5,
+}
+
+pub const NUM_TAGS: usize = 6;
+// flags bits 0-2 are PageBits, bits 3-7 hold the tag of a taken page
+// or the order of a free block's first page
+const TAG_SHIFT: u8 = 3;
+
+impl Tag {
+    pub fn val(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tag::Kernel => "kernel",
+            Tag::PageTable => "page table",
+            Tag::Sched => "sched",
+            Tag::Fs => "fs",
+            Tag::Net => "net",
+            Tag::Driver => "driver",
+        }
+    }
+
+    fn from_val(val: u8) -> Self {
+        match val {
+            1 => Tag::PageTable,
+            2 => Tag::Sched,
+            3 => Tag::Fs,
+            4 => Tag::Net,
+            5 => Tag::Driver,
+            _ => Tag::Kernel,
+        }
+    }
+}
+
+// pages currently allocated per tag
+static mut TAG_PAGES: [usize; NUM_TAGS] = [0; NUM_TAGS];
+
 // num_pages of these structs are written at the start of memory
 pub struct Page {
     flags: u8,
@@ -87,84 +175,480 @@ impl Page {
     pub fn clear_flag(&mut self, flag: PageBits) {
         self.flags &= !(flag.val());
     }
+
+    // only meaningful on the first page of an allocation
+    pub fn tag(&self) -> Tag {
+        Tag::from_val(self.flags >> TAG_SHIFT)
+    }
+
+    pub fn set_tag(&mut self, tag: Tag) {
+        self.flags = (self.flags & ((1 << TAG_SHIFT) - 1)) | (tag.val() << TAG_SHIFT);
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.flags & PageBits::Head.val() != 0
+    }
+
+    // only meaningful on the first page of a free block
+    pub fn order(&self) -> usize {
+        (self.flags >> TAG_SHIFT) as usize
+    }
+
+    fn set_head(&mut self, order: usize) {
+        self.flags = PageBits::Head.val() | ((order as u8) << TAG_SHIFT);
+    }
+}
+
+// a range of pages with its own free lists
+// buddies are found relative to base, so blocks are aligned within the zone
+struct Zone {
+    base: usize,
+    len: usize,
+    free: [List; MAX_ORDER + 1],
+    // free single pages that haven't been merged back, see HOT_PAGES
+    hot: List,
+    // free pages already zeroed for zalloc, see ZERO_POOL_PAGES
+    zeroed: List,
+}
+
+impl Zone {
+    const fn new() -> Self {
+        Zone {
+            base: 0,
+            len: 0,
+            free: [
+                List::new(), List::new(), List::new(), List::new(),
+                List::new(), List::new(), List::new(), List::new(),
+                List::new(), List::new(), List::new(), List::new(),
+                List::new(), List::new(), List::new(), List::new(),
+            ],
+            hot: List::new(),
+            zeroed: List::new(),
+        }
+    }
+
+    // put a free block on the list for its order
+    unsafe fn push(&mut self, i: usize, order: usize) {
+        (*page_desc(i)).set_head(order);
+        let link = page_link(i);
+        *link = Link::new();
+        self.free[order].push_front(link);
+    }
+
+    // free a block, merging it with its buddy for as long as the buddy
+    // is a free block of the same order
+    unsafe fn free_block(&mut self, mut i: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = self.base + ((i - self.base) ^ (1 << order));
+            if buddy + (1 << order) > self.base + self.len {
+                break;
+            }
+            let b = page_desc(buddy);
+            if !(*b).is_head() || (*b).order() != order {
+                break;
+            }
+            self.free[order].remove(page_link(buddy));
+            (*b).clear();
+            i = min(i, buddy);
+            order += 1;
+        }
+        self.push(i, order);
+    }
+
+    // free a run of pages of any length as the largest aligned blocks
+    // that make it up
+    unsafe fn free_run(&mut self, mut i: usize, mut pages: usize) {
+        while pages > 0 {
+            let mut order = 0;
+            while order < MAX_ORDER
+                && (i - self.base) & (1 << order) == 0
+                && (2 << order) <= pages
+            {
+                order += 1;
+            }
+            self.free_block(i, order);
+            i += 1 << order;
+            pages -= 1 << order;
+        }
+    }
+
+    // free a run of pages given back by dealloc
+    unsafe fn free_pages(&mut self, i: usize, pages: usize) {
+        if pages == 1 && self.hot.len() < HOT_PAGES {
+            let link = page_link(i);
+            *link = Link::new();
+            self.hot.push_front(link);
+        } else {
+            self.free_run(i, pages);
+        }
+    }
+
+    // give every page on the hot list back to the buddy lists
+    unsafe fn drain_hot(&mut self) {
+        while let Some(link) = self.hot.pop_front() {
+            self.free_block(link_page(link), 0);
+        }
+    }
+
+    // take a run of pages off the free lists without marking it taken
+    unsafe fn take(&mut self, pages: usize) -> Option<usize> {
+        if pages == 1 {
+            if let Some(link) = self.hot.pop_front() {
+                return Some(link_page(link));
+            }
+        }
+
+        let order = order_for(pages);
+        // smallest block that fits
+        let mut k = order;
+        while k <= MAX_ORDER && self.free[k].is_empty() {
+            k += 1;
+        }
+        if k > MAX_ORDER {
+            return None;
+        }
+        let i = link_page(self.free[k].pop_front().unwrap());
+        (*page_desc(i)).clear();
+
+        // split it down to size, the upper halves go back on their lists
+        while k > order {
+            k -= 1;
+            self.push(i + (1 << k), k);
+        }
+
+        // pages that don't fill the block are handed back
+        self.free_run(i + pages, (1 << order) - pages);
+        Some(i)
+    }
+
+    // take a run of pages made up of neighbouring free blocks, for when
+    // no one block is big enough
+    // walks the whole zone, and expects the hot list and zeroed pool to
+    // be drained so every free page is part of a block
+    unsafe fn take_run(&mut self, pages: usize) -> Option<usize> {
+        let end = self.base + self.len;
+        let mut start = self.base;
+        let mut i = self.base;
+        while i - start < pages {
+            if i >= end {
+                return None;
+            }
+            let p = page_desc(i);
+            if (*p).is_head() {
+                i += 1 << (*p).order();
+            } else {
+                // taken, the run has to start after it
+                i += 1;
+                start = i;
+            }
+        }
+
+        // pull the blocks making up the run off their lists
+        let mut j = start;
+        while j < i {
+            let p = page_desc(j);
+            let order = (*p).order();
+            self.free[order].remove(page_link(j));
+            (*p).clear();
+            j += 1 << order;
+        }
+        // the last block may run past the end, hand that part back
+        self.free_run(start + pages, i - start - pages);
+        Some(start)
+    }
+
+    unsafe fn alloc(&mut self, pages: usize, tag: Tag) -> *mut u8 {
+        let i = match self.take(pages) {
+            Some(i) => i,
+            None => {
+                // the held back pages may merge into something big enough
+                self.drain_hot();
+                self.drain_zeroed();
+                match self.take(pages) {
+                    Some(i) => i,
+                    None => match self.take_run(pages) {
+                        Some(i) => i,
+                        // return a null mutable pointer to indicate no available pages
+                        None => return null_mut(),
+                    },
+                }
+            }
+        };
+        mark_taken(i, pages, tag);
+        trace!(PageAlloc, ALLOC_START + PAGE_SIZE * i, pages);
+        (ALLOC_START + PAGE_SIZE * i) as *mut u8
+    }
+
+    // hand out a page from the zeroed pool, null if it's empty
+    unsafe fn alloc_zeroed(&mut self, tag: Tag) -> *mut u8 {
+        match self.zeroed.pop_front() {
+            Some(link) => {
+                // the Link was the only thing written to the page
+                *link = Link::new();
+                let i = link_page(link);
+                mark_taken(i, 1, tag);
+                trace!(PageAlloc, ALLOC_START + PAGE_SIZE * i, 1);
+                (ALLOC_START + PAGE_SIZE * i) as *mut u8
+            }
+            None => null_mut(),
+        }
+    }
+
+    // give the zeroed pool back to the buddy lists
+    unsafe fn drain_zeroed(&mut self) {
+        while let Some(link) = self.zeroed.pop_front() {
+            self.free_block(link_page(link), 0);
+        }
+    }
+
+    // number of free blocks of an order
+    fn blocks(&self, order: usize) -> usize {
+        self.free[order].len()
+    }
+}
+
+// free lists for the CMA region and for everything after it
+static mut CMA_ZONE: Zone = Zone::new();
+static mut MAIN_ZONE: Zone = Zone::new();
+// pages that fit between ALLOC_START and the end of the heap
+static mut NUM_PAGES: usize = 0;
+// references held on each allocation, kept on its first page
+// alloc gives out one, every mapping of the allocation takes another
+static mut REFS: *mut u16 = null_mut();
+// first page of the allocation each taken page belongs to, so finding
+// the allocation behind an address doesn't walk back through it
+static mut HEADS: *mut u32 = null_mut();
+
+unsafe fn page_desc(i: usize) -> *mut Page {
+    (HEAP_START as *mut Page).add(i)
+}
+
+unsafe fn page_link(i: usize) -> *mut Link {
+    (ALLOC_START + i * PAGE_SIZE) as *mut Link
+}
+
+unsafe fn link_page(link: *mut Link) -> usize {
+    (link as usize - ALLOC_START) / PAGE_SIZE
+}
+
+unsafe fn zone_of(i: usize) -> &'static mut Zone {
+    if i < CMA_PAGES {
+        &mut CMA_ZONE
+    } else {
+        &mut MAIN_ZONE
+    }
+}
+
+// mark a run of pages taken and charge it to a tag
+unsafe fn mark_taken(i: usize, pages: usize, tag: Tag) {
+    for j in i..i + pages {
+        // set number pages requested to taken
+        (*page_desc(j)).set_flag(PageBits::Taken);
+        *HEADS.add(j) = i as u32;
+    }
+    (*page_desc(i + pages - 1)).set_flag(PageBits::Last);
+    (*page_desc(i)).set_tag(tag);
+    *REFS.add(i) = 1;
+    TAG_PAGES[tag.val() as usize] += pages;
+}
+
+// smallest order whose blocks hold this many pages
+fn order_for(pages: usize) -> usize {
+    let mut order = 0;
+    while (1 << order) < pages {
+        order += 1;
+    }
+    order
 }
 
 // initialize the page allocator
 pub fn init() {
     unsafe {
         let num_pages = HEAP_SIZE / PAGE_SIZE;
-        let ptr = HEAP_START as *mut Page;
 
         for i in 0..num_pages {
-            (*ptr.add(i)).clear();
+            (*page_desc(i)).clear();
         }
 
-        // start of usable memory is after page table
+        // refcounts come right after the page structs
+        REFS = align_val(HEAP_START + num_pages * size_of::<Page>(), 1) as *mut u16;
+        for i in 0..num_pages {
+            *REFS.add(i) = 0;
+        }
+
+        HEADS = align_val(REFS.add(num_pages) as usize, 2) as *mut u32;
+
+        // start of usable memory is after page table, refcounts and heads
         // ALLOC_START = align_val(HEAP_START + num_pages * size_of::<Page>(), PAGE_ORDER);
-        ALLOC_START = align_val(HEAP_START + num_pages * size_of::<Page>(), PAGE_ORDER);
+        ALLOC_START = align_val(HEADS.add(num_pages) as usize, PAGE_ORDER);
+        // the page table itself takes up a few of the pages it describes
+        let heap_end = HEAP_START + HEAP_SIZE;
+        NUM_PAGES = (heap_end - ALLOC_START) / PAGE_SIZE;
+
+        // take over from the early bump allocator: whatever it handed out
+        // stays taken, the rest of its arena is free like any other page
+        let mut free_end = NUM_PAGES;
+        let bump_low = bump::retire() & !(PAGE_SIZE - 1);
+        if bump_low < heap_end {
+            let first = (bump_low - ALLOC_START) / PAGE_SIZE;
+            mark_taken(first, NUM_PAGES - first, Tag::Kernel);
+            free_end = first;
+        }
+
+        CMA_ZONE.base = 0;
+        CMA_ZONE.len = CMA_PAGES;
+        CMA_ZONE.free_run(0, CMA_PAGES);
+        MAIN_ZONE.base = CMA_PAGES;
+        MAIN_ZONE.len = NUM_PAGES - CMA_PAGES;
+        MAIN_ZONE.free_run(CMA_PAGES, free_end - CMA_PAGES);
     }
+    refill_zero_pool();
 }
 
 // allocate a new page in memory
 pub fn alloc(pages: usize) -> *mut u8 {
+    alloc_tagged(pages, Tag::Kernel)
+}
+
+// allocate pages and charge them to a subsystem
+pub fn alloc_tagged(pages: usize, tag: Tag) -> *mut u8 {
     assert!(pages > 0);
-    unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
-        let ptr = HEAP_START as *mut Page;
-        for i in 0..num_pages - pages {
-            let mut found = false;
-
-            if (*ptr.add(i)).is_free() {
-                // page is free
-                found = true;
-                for j in i..i + pages {
-                    if (*ptr.add(j)).is_taken() {
-                        found = false;
-                        break;
-                    }
-                }
-            }
+    // general allocations never dip into the CMA region
+    unsafe { MAIN_ZONE.alloc(pages, tag) }
+}
 
-            if found {
-                for k in i..i + pages - 1 {
-                    // set number pages requested to taken
-                    (*ptr.add(k)).set_flag(PageBits::Taken);
-                }
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Taken);
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Last);
+// allocate a contiguous run of pages from the reserved CMA region
+// meant for large buffers handed to devices (framebuffers, virtio rings)
+pub fn cma_alloc(pages: usize) -> *mut u8 {
+    assert!(pages > 0);
+    unsafe { CMA_ZONE.alloc(pages, Tag::Driver) }
+}
 
-                return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
-            }
-        }
-    }
-    // return a null mutable pointer to indicate no available pages
-    null_mut()
+// free a run of pages given out by cma_alloc
+pub fn cma_free(page_ptr: *mut u8) {
+    assert!(is_cma(page_ptr), "Not a CMA allocation!");
+    dealloc(page_ptr);
+}
+
+// check whether an address falls inside the CMA region
+pub fn is_cma(addr: *mut u8) -> bool {
+    let addr = addr as usize;
+    unsafe { addr >= ALLOC_START && addr < ALLOC_START + CMA_PAGES * PAGE_SIZE }
 }
 
 // deallocate a page given is pointer
+// drops the caller's reference, the pages are only freed once nothing
+// maps them any more
 pub fn dealloc(page_ptr: *mut u8) {
     assert!(!page_ptr.is_null());
     unsafe {
-        let page_addr = HEAP_START + (page_ptr as usize - ALLOC_START) / PAGE_SIZE;
         // make sure address for page struct is within memory
-        assert!(page_addr >= HEAP_START && page_addr < HEAP_START + HEAP_SIZE);
-        let mut p = page_addr as *mut Page;
+        assert!(page_ptr as usize >= ALLOC_START);
+        let first = (page_ptr as usize - ALLOC_START) / PAGE_SIZE;
+        assert!(first < NUM_PAGES);
+        assert!((*page_desc(first)).is_taken(), "Possible double-free!");
+        put_ref(first);
+    }
+}
 
-        while (*p).is_taken() && !(*p).is_last() {
-            (*p).clear();
-            p = p.add(1);
-        }
+// drop a reference on the allocation starting at page i, freeing it
+// with the last one
+unsafe fn put_ref(first: usize) {
+    let refs = REFS.add(first);
+    assert!(*refs > 0, "Possible double-free!");
+    *refs -= 1;
+    if *refs > 0 {
+        return;
+    }
 
-        // didn't reach last page before hitting untaken page
-        assert!((*p).is_last() == true, "Possible double-free!");
+    let mut p = page_desc(first);
+    let tag = (*p).tag();
+    let mut freed = 1;
 
+    while (*p).is_taken() && !(*p).is_last() {
         (*p).clear();
+        p = p.add(1);
+        freed += 1;
+    }
+
+    // didn't reach last page before hitting untaken page
+    assert!((*p).is_last() == true, "Possible double-free!");
+
+    (*p).clear();
+    TAG_PAGES[tag.val() as usize] -= freed;
+    trace!(PageFree, ALLOC_START + PAGE_SIZE * first, freed);
+    zone_of(first).free_pages(first, freed);
+}
+
+// first page of the allocation holding a physical address, None if the
+// address isn't in an allocation (kernel image, MMIO, free pages)
+fn alloc_start(paddr: usize) -> Option<usize> {
+    unsafe {
+        if paddr < ALLOC_START || paddr >= ALLOC_START + NUM_PAGES * PAGE_SIZE {
+            return None;
+        }
+        let i = (paddr - ALLOC_START) / PAGE_SIZE;
+        if (*page_desc(i)).is_free() {
+            return None;
+        }
+        Some(*HEADS.add(i) as usize)
+    }
+}
+
+// address of the first page of the allocation holding addr, None if
+// addr isn't allocated
+pub fn alloc_base(addr: usize) -> Option<usize> {
+    alloc_start(addr).map(|i| unsafe { ALLOC_START + i * PAGE_SIZE })
+}
+
+// take a reference on the allocation holding a physical address, so it
+// isn't freed while something still maps it
+// returns false, taking nothing, for addresses that aren't allocated
+// (kernel image, MMIO, free pages)
+pub fn get_page(paddr: usize) -> bool {
+    match alloc_start(paddr) {
+        Some(i) => unsafe {
+            let refs = REFS.add(i);
+            assert!(*refs < u16::max_value(), "Page refcount overflow!");
+            *refs += 1;
+            true
+        },
+        None => false,
+    }
+}
+
+// drop a reference taken by get_page, freeing the allocation if it was
+// the last one
+pub fn put_page(paddr: usize) {
+    let i = alloc_start(paddr).expect("Not an allocated page!");
+    unsafe {
+        put_ref(i);
+    }
+}
+
+// references held on the allocation holding a physical address,
+// 0 if it isn't allocated
+pub fn page_refs(paddr: usize) -> usize {
+    match alloc_start(paddr) {
+        Some(i) => unsafe { *REFS.add(i) as usize },
+        None => 0,
     }
 }
 
 // allocate and zero a page(s)
 pub fn zalloc(pages: usize) -> *mut u8 {
-    let ret = alloc(pages);
+    zalloc_tagged(pages, Tag::Kernel)
+}
+
+// allocate and zero pages charged to a subsystem
+pub fn zalloc_tagged(pages: usize, tag: Tag) -> *mut u8 {
+    if pages == 1 {
+        let ret = unsafe { MAIN_ZONE.alloc_zeroed(tag) };
+        if !ret.is_null() {
+            return ret;
+        }
+    }
+    let ret = alloc_tagged(pages, tag);
     if !ret.is_null() {
         let size = (PAGE_SIZE * pages) / 8;
         let big_ptr = ret as *mut u64;
@@ -172,17 +656,39 @@ pub fn zalloc(pages: usize) -> *mut u8 {
             // using big_ptr so we go double-word (DW) writes
             // instead of single byte (SB)
             unsafe {
+                (*big_ptr.add(i)) = 0;
             }
         }
     }
     ret
 }
 
+// top up the pool of zeroed pages zalloc(1) takes from
+// cheap to call when it's already full, so call it whenever the kernel
+// has nothing better to do
+pub fn refill_zero_pool() {
+    unsafe {
+        while MAIN_ZONE.zeroed.len() < ZERO_POOL_PAGES {
+            let i = match MAIN_ZONE.take(1) {
+                Some(i) => i,
+                None => break,
+            };
+            let big_ptr = (ALLOC_START + PAGE_SIZE * i) as *mut u64;
+            for j in 0..PAGE_SIZE / 8 {
+                (*big_ptr.add(j)) = 0;
+            }
+            let link = page_link(i);
+            *link = Link::new();
+            MAIN_ZONE.zeroed.push_front(link);
+        }
+    }
+}
+
 /// Print all page allocations
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
     unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let num_pages = NUM_PAGES;
         let mut beg = HEAP_START as *const Page;
         let end = beg.add(num_pages);
         let alloc_beg = ALLOC_START;
@@ -190,8 +696,9 @@ pub fn print_page_allocations() {
         println!();
         println!(
             "PAGE ALLOCATION TABLE\nMETA: {:p} -> {:p}\nPHYS: \
-             0x{:x} -> 0x{:x}",
-            beg, end, alloc_beg, alloc_end
+             0x{:x} -> 0x{:x}\nCMA : 0x{:x} -> 0x{:x}",
+            beg, end, alloc_beg, alloc_end,
+            alloc_beg, alloc_beg + CMA_PAGES * PAGE_SIZE
         );
         println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
         let mut num = 0;
@@ -206,6 +713,10 @@ pub fn print_page_allocations() {
                         let end = beg as usize;
                         let memaddr = ALLOC_START + (end - HEAP_START) * PAGE_SIZE + PAGE_SIZE - 1;
                         print!("0x{:x}: {:>3} page(s)", memaddr, (end - start + 1));
+                        let refs = *REFS.add(start - HEAP_START);
+                        if refs > 1 {
+                            print!(", {} refs", refs);
+                        }
                         println!(".");
                         break;
                     }
@@ -229,10 +740,172 @@ pub fn print_page_allocations() {
     }
 }
 
+/// Print a histogram of free-run lengths in the general (non-CMA) area
+/// and its external fragmentation index:
+/// 1 - (largest free run / total free pages)
+/// 0% means all free memory is one run, values near 100% mean free
+/// memory is scattered in runs far smaller than the total.
+/// Ends with the number of free buddy blocks of each order and the
+/// lengths of the single-page hot list and zeroed pool.
+pub fn frag_report() {
+    // bucket n counts free runs of 2^n to 2^(n+1) - 1 pages
+    const BUCKETS: usize = 16;
+    let mut hist = [0usize; BUCKETS];
+    let mut free = 0;
+    let mut largest = 0;
+
+    unsafe {
+        let num_pages = NUM_PAGES;
+        let ptr = HEAP_START as *const Page;
+        let mut i = CMA_PAGES;
+        while i < num_pages {
+            if (*ptr.add(i)).is_taken() {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < num_pages && (*ptr.add(i)).is_free() {
+                i += 1;
+            }
+            let run = i - start;
+            free += run;
+            if run > largest {
+                largest = run;
+            }
+            // floor(log2(run)), clamped to the last bucket
+            let order = size_of::<usize>() * 8 - 1 - run.leading_zeros() as usize;
+            hist[core::cmp::min(order, BUCKETS - 1)] += 1;
+        }
+    }
+
+    println!();
+    println!("FREE RUN HISTOGRAM");
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    for (order, count) in hist.iter().enumerate() {
+        if *count == 0 {
+            continue;
+        }
+        if order == BUCKETS - 1 {
+            println!("{:>6}+        pages: {:>5} run(s)", 1usize << order, count);
+        } else {
+            println!(
+                "{:>6} - {:>6} pages: {:>5} run(s)",
+                1usize << order,
+                (1usize << (order + 1)) - 1,
+                count
+            );
+        }
+    }
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    // index in tenths of a percent to avoid floats
+    let index = if free == 0 { 0 } else { 1000 - largest * 1000 / free };
+    println!(
+        "Free: {} pages, largest run: {} pages, fragmentation: {}.{}%",
+        free,
+        largest,
+        index / 10,
+        index % 10
+    );
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    for order in 0..=MAX_ORDER {
+        let blocks = unsafe { MAIN_ZONE.blocks(order) };
+        if blocks != 0 {
+            println!(
+                "order {:>2} ({:>5} pages): {:>5} free block(s)",
+                order,
+                1usize << order,
+                blocks
+            );
+        }
+    }
+    println!("hot list          : {:>5} page(s)", unsafe { MAIN_ZONE.hot.len() });
+    println!("zeroed pool       : {:>5} page(s)", unsafe { MAIN_ZONE.zeroed.len() });
+    println!();
+}
+
+/// Print how many pages each subsystem has allocated
+pub fn meminfo() {
+    unsafe {
+        let num_pages = NUM_PAGES;
+        let mut total = 0;
+        println!();
+        println!("MEMINFO");
+        println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+        for t in 0..NUM_TAGS {
+            let tag = Tag::from_val(t as u8);
+            let pages = TAG_PAGES[t];
+            total += pages;
+            println!(
+                "{:<10}: {:>5} pages ({:>9} bytes).",
+                tag.name(),
+                pages,
+                pages * PAGE_SIZE
+            );
+        }
+        println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+        println!(
+            "{:<10}: {:>5} pages ({:>9} bytes).",
+            "total",
+            total,
+            total * PAGE_SIZE
+        );
+        println!(
+            "{:<10}: {:>5} pages ({:>9} bytes).",
+            "free",
+            num_pages - total,
+            (num_pages - total) * PAGE_SIZE
+        );
+        println!();
+    }
+}
+
+// pages currently charged to a subsystem
+pub fn tag_pages(tag: Tag) -> usize {
+    unsafe { TAG_PAGES[tag.val() as usize] }
+}
+
 // ==========================================================================================================
 // MMU routine
 // ==========================================================================================================
 
+// paging mode, Sv39 unless built with the sv48 feature
+// Sv39: 3 levels of tables, 39-bit virtual addresses
+// Sv48: 4 levels of tables, 48-bit virtual addresses
+#[cfg(not(feature = "sv48"))]
+pub const LEVELS: usize = 3;
+#[cfg(not(feature = "sv48"))]
+pub const SATP_MODE: usize = 8;
+#[cfg(not(feature = "sv48"))]
+pub const MODE_NAME: &str = "Sv39";
+
+#[cfg(feature = "sv48")]
+pub const LEVELS: usize = 4;
+#[cfg(feature = "sv48")]
+pub const SATP_MODE: usize = 9;
+#[cfg(feature = "sv48")]
+pub const MODE_NAME: &str = "Sv48";
+
+// number of virtual address bits the mode translates
+pub const VA_BITS: usize = 12 + 9 * LEVELS;
+
+// each vpn is 9 bits (0b1_1111_1111)
+// VPN[0] = virt addr bits 20-12
+// VPN[1] = virt addr 29-21
+// VPN[2] = virt addr 38-30
+// VPN[3] = virt addr 47-39 (Sv48 only)
+fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + level * 9)) & 0x1ff
+}
+
+// addresses are sign extended from the top translated bit
+fn sign_extend(vaddr: usize) -> usize {
+    if vaddr & (1 << (VA_BITS - 1)) != 0 {
+        vaddr | !((1usize << VA_BITS) - 1)
+    } else {
+        vaddr
+    }
+}
+
 // all options are unsigned 64-bit regs
 // and our copy/clone fn are implicit
 #[repr(i64)]
@@ -247,6 +920,9 @@ pub enum EntryBits {
     Global = 1 << 5,
     Access = 1 << 6,
     Dirty = 1 << 7,
+    // bits 8-9 are left to software (RSW)
+    Cow = 1 << 8, // write on a copy of the page, see cow_fault
+    Ref = 1 << 9, // the mapping holds a reference on its page, see map
 
     // convenience combinations
     RW = 1 << 1 | 1 << 2,
@@ -303,7 +979,7 @@ pub struct Table {
 
 impl Table {
     pub fn len() -> usize {
-        size_of::<Table>()
+        512
     }
 }
 
@@ -316,33 +992,13 @@ impl Table {
 pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: i64, level: usize) {
     // make sure we have a leaf
     assert!(bits & 0xe != 0);
+    assert!(level < LEVELS);
 
-    // each vpn is 9 bits (0b1_1111_1111)
-    let vpn = [
-        // VPN[0] = virt addr bits 20-12
-        (vaddr >> 12) & 0x1ff,
-        // VPN[1] = virt addr 29-21
-        (vaddr >> 21) & 0x1ff,
-        // VPN[2] = virt addr 38-30
-        (vaddr >> 30) & 0x1ff,
-
-    ];
-
-    // each ppn is 9 bits except the last 1 is 26 bits
-    let ppn = [
-        // PPN[0] = paddr[20:12]
-        (paddr >> 12) & 0x1ff,
-        // PPN[1] = paddr[29:21]
-        (paddr >> 21) & 0x1ff,
-        // PPN[2] = paddr[55:30]
-        (paddr >> 30) & 0x3ff_ffff,
-    ];
-
-    let mut v = &mut root.entries[vpn[2]];
-
-    for i in (level..2).rev() {
+    let mut v = &mut root.entries[vpn(vaddr, LEVELS - 1)];
+
+    for i in (level..LEVELS - 1).rev() {
         if !v.is_valid() {
-            let page = zalloc(1);
+            let page = zalloc_tagged(1, Tag::PageTable);
 
             // v's entry is a 64-bit heap address that's 4096 byte aligned
             // shifted right by 2 to make space for flags
@@ -356,60 +1012,173 @@ pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: i64, level: usize
         // and would be the page table for this lower set of pages
         let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
         // get the address of the next page table starting point
-        v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+        v = unsafe { entry.add(vpn(vaddr, i)).as_mut().unwrap() };
     }
     // after the prev loop, v is now pointing to the
     // entry loc in the mapping table (virt->phys)
 
     // need to shift paddr vals to correct value for page table entry
-    let entry = (ppn[2] << 28) as i64 |   // PPN[2] = [53:28]
-    (ppn[1] << 19) as i64 |   // PPN[1] = [27:19]
-    (ppn[0] << 10) as i64 |   // PPN[0] = [18:10]
+    // the 44-bit PPN = paddr[55:12] sits at [53:10] in both modes,
+    // only how it splits into PPN[n] fields differs
+    let entry = (((paddr >> 12) & ((1 << 44) - 1)) << 10) as i64 |   // PPN
     bits |                    // Specified bits, such as User, Read, Write, etc
     EntryBits::Valid.val();   // Valid bit
 
+    // the mapping holds a reference on the page if it's allocated, and a
+    // remapped entry lets go of the one it held
+    let entry = if get_page(paddr) {
+        entry | EntryBits::Ref.val()
+    } else {
+        entry & !EntryBits::Ref.val()
+    };
+    put_entry(v);
+
     v.set_entry(entry);
 
 }
 
+// drop the reference a leaf entry holds on its page, if it holds one
+fn put_entry(entry: &Entry) {
+    if entry.is_valid() && entry.is_leaf() && entry.get_entry() & EntryBits::Ref.val() != 0 {
+        put_page(((entry.get_entry() & !0x3ff) << 2) as usize);
+    }
+}
+
+// free every table below root and drop the references its mappings
+// hold on their pages
 pub fn unmap(root: &mut Table) {
-    for lv2 in 0..Table::len() {
-        let ref entry_lv2 = root.entries[lv2];
-        if entry_lv2.is_valid() && entry_lv2.is_branch() {
+    unmap_table(root, LEVELS - 1);
+}
+
+// drop the references held by the leaves of a table at level and free
+// every table below it
+fn unmap_table(table: &mut Table, level: usize) {
+    for i in 0..Table::len() {
+        let ref entry = table.entries[i];
+        if entry.is_valid() && entry.is_leaf() {
+            put_entry(entry);
+        } else if entry.is_valid() && level > 0 {
             // valid entry, free it and the lower table entries
-            let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
-            let table_lv1 = unsafe {
-                (memaddr_lv1 as *mut Table).as_mut().unwrap()
-            };
-            for lv1 in 0..Table::len() {
-                let ref entry_lv1 = table_lv1.entries[lv1];
-                if entry_lv1.is_valid() && entry_lv1.is_branch() {
-                    let memaddr_lv0 = (entry_lv1.get_entry() & !0x3ff) << 2;
+            let memaddr = (entry.get_entry() & !0x3ff) << 2;
+            let next = unsafe { (memaddr as *mut Table).as_mut().unwrap() };
+            unmap_table(next, level - 1);
+            dealloc(memaddr as *mut u8);
+        }
+    }
+}
 
-                    // last level, free it
-                    dealloc(memaddr_lv0 as *mut u8);
-                }
+// Unmap every page in [vaddr, vaddr + len) of the address space tagged
+// asid and free the tables that are left empty (root itself always stays).
+// free: drop the references the mappings held, freeing pages nothing
+// else maps. Otherwise those references pass to the caller, who has to
+// put_page them later, see unmap_range_with to be told which pages.
+// Superpages have to be unmapped whole, returns false without unmapping
+// anything if one sticks out of either end of the range.
+pub fn unmap_range(root: &mut Table, asid: u16, vaddr: usize, len: usize, free: bool) -> bool {
+    unmap_range_with(root, asid, vaddr, len, |_, paddr| {
+        if free {
+            put_page(paddr);
+        }
+    })
+}
+
+// unmap_range, handing each unmapped page that the mapping held a
+// reference on to f(vaddr, paddr) along with that reference
+pub fn unmap_range_with<F: FnMut(usize, usize)>(
+    root: &mut Table,
+    asid: u16,
+    vaddr: usize,
+    len: usize,
+    mut f: F,
+) -> bool {
+    assert!(vaddr % PAGE_SIZE == 0 && len % PAGE_SIZE == 0);
+    if len == 0 {
+        return true;
+    }
+    // compare untranslated bits only, so sign extended addresses work
+    let start = vaddr & ((1 << VA_BITS) - 1);
+    let end = start + len;
+    // only a leaf holding one of the ends can stick out
+    for &addr in [start, end - 1].iter() {
+        if let Some((lo, hi)) = leaf_span(root, addr) {
+            if lo < start || hi > end {
+                return false;
             }
+        }
+    }
+    unmap_range_table(root, asid, LEVELS - 1, 0, start, end, &mut f);
+    true
+}
 
-            dealloc(memaddr_lv1 as *mut u8);
+// the untranslated range covered by the leaf mapping vaddr, if any
+fn leaf_span(root: &Table, vaddr: usize) -> Option<(usize, usize)> {
+    let mut table = root;
+    for level in (0..LEVELS).rev() {
+        let v = &table.entries[vpn(vaddr, level)];
+        if v.is_invalid() {
+            return None;
+        }
+        if v.is_leaf() {
+            let size = 1usize << (12 + level * 9);
+            let lo = vaddr & !(size - 1);
+            return Some((lo, lo + size));
+        }
+        let next = ((v.get_entry() & !0x3ff) << 2) as *const Table;
+        table = unsafe { next.as_ref().unwrap() };
+    }
+    None
+}
 
+// unmap [start, end) from a table at level covering vbase and up,
+// returns true if the table has no valid entries left
+fn unmap_range_table<F: FnMut(usize, usize)>(
+    table: &mut Table,
+    asid: u16,
+    level: usize,
+    vbase: usize,
+    start: usize,
+    end: usize,
+    f: &mut F,
+) -> bool {
+    // each entry at this level covers 4 KiB, 2 MiB, 1 GiB, or 512 GiB
+    let size = 1usize << (12 + level * 9);
+    for i in 0..Table::len() {
+        let lo = vbase + i * size;
+        let hi = lo + size;
+        let entry = &mut table.entries[i];
+        if hi <= start || lo >= end || entry.is_invalid() {
+            continue;
+        }
+
+        let paddr = ((entry.get_entry() & !0x3ff) << 2) as usize;
+        if entry.is_leaf() {
+            let held = entry.get_entry() & EntryBits::Ref.val() != 0;
+            entry.set_entry(EntryBits::None.val());
+            sfence_page(sign_extend(lo), asid);
+            // only once nothing can reach the page through this entry
+            if held {
+                f(sign_extend(lo), paddr);
+            }
+        } else if level > 0 {
+            let next = unsafe { (paddr as *mut Table).as_mut().unwrap() };
+            if unmap_range_table(next, asid, level - 1, lo, start, end, f) {
+                // nothing left below, reclaim the table
+                // a flush by address only covers leaves, the hart may still
+                // hold this entry and walk into the table, so drop every
+                // translation of the address space before the table is freed
+                entry.set_entry(EntryBits::None.val());
+                sfence_asid(asid);
+                dealloc(paddr as *mut u8);
+            }
         }
     }
+    table.entries.iter().all(|e| e.is_invalid())
 }
 
 pub fn virt_to_phys(root: &Table, vaddr: usize) ->  Option<usize> {
     // Walk the page table
-    let vpn = [
-        // VPN[0] = virt addr bits 20-12
-        (vaddr >> 12) & 0x1ff,
-        // VPN[1] = virt addr 29-21
-        (vaddr >> 21) & 0x1ff,
-        // VPN[2] = virt addr 38-30
-        (vaddr >> 30) & 0x1ff,
-    ];
-
-    let mut v = &root.entries[vpn[2]];
-    for i in (0..=2).rev() {
+    let mut v = &root.entries[vpn(vaddr, LEVELS - 1)];
+    for i in (0..LEVELS).rev() {
         if v.is_invalid() {
             // invalid, send a page fault
             break;
@@ -422,12 +1191,579 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) ->  Option<usize> {
             let addr = ((v.get_entry() << 2) as usize) & !off_mask;
             return Some(addr | vaddr_pgoff);
         }
+        else if i == 0 {
+            // a branch in the last level table is malformed
+            break;
+        }
 
         let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
 
-        v = unsafe { entry.add(vpn[i-1]).as_ref().unwrap() };
+        v = unsafe { entry.add(vpn(vaddr, i - 1)).as_ref().unwrap() };
+    }
+
+    None
+}
+
+// ==========================================================================================================
+// ASIDs
+// ==========================================================================================================
+
+// SATP layout (both Sv39 and Sv48)
+// [63:60] MODE | [59:44] ASID | [43:0] PPN of the root table
+// Tagging an address space with an ASID lets the TLB keep its entries
+// across satp switches, so only what actually changed needs a flush.
+// A hart implements anywhere from 0 to 16 ASID bits (ASIDLEN), found by
+// asid_init. With none, every address space is ASID 0 and each switch
+// has to flush.
+
+pub const ASID_BITS: usize = 16;
+const NUM_ASIDS: usize = 1 << ASID_BITS;
+
+// ASID bits this hart implements, set by asid_init
+static mut ASID_LEN: usize = 0;
+// one bit per ASID, set when taken
+// ASID 0 is kept for the kernel and never handed out
+static mut ASID_MAP: [u64; NUM_ASIDS / 64] = [0; NUM_ASIDS / 64];
+// one bit per ASID given back since it was last switched to, the TLB may
+// still hold the old owner's translations
+static mut ASID_STALE: [u64; NUM_ASIDS / 64] = [0; NUM_ASIDS / 64];
+// where the next search starts, so freed ASIDs aren't reused right away
+static mut ASID_NEXT: usize = 1;
+
+// find out how many ASID bits the hart has: all ones are written to the
+// field and whatever sticks is implemented
+// Bare mode leaves the other satp fields unspecified, so the probe goes
+// out with a real mode: on the current root if paging is on, otherwise
+// on an empty table. kmain runs in M-mode, which satp doesn't translate,
+// so that table is never walked.
+// has to run after init and before the first asid_alloc
+pub fn asid_init() {
+    let old: usize;
+    let probed: usize;
+    unsafe {
+        asm!("csrr $0, satp" : "=r"(old) ::: "volatile");
     }
+    let empty = if old >> 60 == SATP_MODE {
+        null_mut()
+    } else {
+        let table = zalloc_tagged(1, Tag::PageTable);
+        assert!(!table.is_null(), "No page to probe ASIDs with!");
+        table
+    };
+    let satp = if empty.is_null() {
+        old
+    } else {
+        build_satp(unsafe { &*(empty as *const Table) }, 0)
+    };
+    unsafe {
+        asm!("csrw satp, $0" :: "r"(satp | (0xffff << 44)) :: "volatile");
+        asm!("csrr $0, satp" : "=r"(probed) ::: "volatile");
+        asm!("csrw satp, $0" :: "r"(old) :: "volatile");
+        ASID_LEN = ((probed >> 44) & 0xffff).count_ones() as usize;
+    }
+    // the probe ran under whatever ASID it wrote
+    sfence_all();
+    if !empty.is_null() {
+        dealloc(empty);
+    }
+}
 
+// ASID bits this hart implements
+pub fn asid_len() -> usize {
+    unsafe { ASID_LEN }
+}
+
+// take a free ASID, None if all are in use
+// always ASID 0 on a hart without ASIDs, see set_satp
+pub fn asid_alloc() -> Option<u16> {
+    unsafe {
+        if ASID_LEN == 0 {
+            return Some(0);
+        }
+        let num = 1 << ASID_LEN;
+        for n in 0..num {
+            let asid = (ASID_NEXT + n) % num;
+            if asid == 0 {
+                continue;
+            }
+            let (word, bit) = (asid / 64, asid % 64);
+            if ASID_MAP[word] & (1 << bit) == 0 {
+                ASID_MAP[word] |= 1 << bit;
+                ASID_NEXT = (asid + 1) % num;
+                return Some(asid as u16);
+            }
+        }
+    }
     None
 }
 
+// give back an ASID, its next owner flushes what the TLB still holds
+// for it on the first switch to it
+pub fn asid_free(asid: u16) {
+    let asid = asid as usize;
+    unsafe {
+        if ASID_LEN == 0 {
+            return;
+        }
+        assert!(asid != 0, "ASID 0 belongs to the kernel!");
+        let (word, bit) = (asid / 64, asid % 64);
+        assert!(ASID_MAP[word] & (1 << bit) != 0, "Possible double-free!");
+        ASID_MAP[word] &= !(1 << bit);
+        ASID_STALE[word] |= 1 << bit;
+    }
+}
+
+// build the satp value that translates through root, tagged with asid
+pub fn build_satp(root: &Table, asid: u16) -> usize {
+    let ppn = (root as *const Table as usize) >> 12;
+    (SATP_MODE << 60) | ((asid as usize) << 44) | (ppn & ((1 << 44) - 1))
+}
+
+// switch this hart to another address space
+// the TLB tells address spaces apart by ASID, so there's only a flush
+// if the hart has no ASIDs or the ASID was given back by its last owner
+pub fn set_satp(satp: usize) {
+    let asid = (satp >> 44) & 0xffff;
+    unsafe {
+        asm!("csrw satp, $0" :: "r"(satp) :: "volatile");
+        if ASID_LEN == 0 {
+            sfence_all();
+            return;
+        }
+        let (word, bit) = (asid / 64, asid % 64);
+        if ASID_STALE[word] & (1 << bit) != 0 {
+            ASID_STALE[word] &= !(1 << bit);
+            sfence_asid(asid as u16);
+        }
+    }
+}
+
+// flush one page of one address space
+pub fn sfence_page(vaddr: usize, asid: u16) {
+    unsafe {
+        asm!("sfence.vma $0, $1" :: "r"(vaddr), "r"(asid as usize) :: "volatile");
+    }
+}
+
+// flush everything belonging to one address space
+// global mappings are left alone
+pub fn sfence_asid(asid: u16) {
+    unsafe {
+        asm!("sfence.vma zero, $0" :: "r"(asid as usize) :: "volatile");
+    }
+}
+
+// flush every translation on this hart
+pub fn sfence_all() {
+    unsafe {
+        asm!("sfence.vma" :::: "volatile");
+    }
+}
+
+// ==========================================================================================================
+// Copy-on-write
+// ==========================================================================================================
+
+// Map a 4096-byte page so that it's shared until the first store.
+// A writable mapping is installed read-only with the software Cow bit,
+// the store page fault then lands in cow_fault which hands the writer
+// its own copy. Mapping the same page this way into several tables
+// shares it, the refcount keeps it alive until the last copy is made.
+pub fn map_cow(root: &mut Table, vaddr: usize, paddr: usize, bits: i64) {
+    let bits = if bits & EntryBits::Write.val() != 0 {
+        (bits & !EntryBits::Write.val()) | EntryBits::Cow.val()
+    } else {
+        bits
+    };
+    map(root, vaddr, paddr, bits, 0);
+}
+
+// the level 0 entry mapping vaddr, if there is one
+fn leaf_entry(root: &mut Table, vaddr: usize) -> Option<&mut Entry> {
+    let mut v = &mut root.entries[vpn(vaddr, LEVELS - 1)];
+    for i in (0..LEVELS - 1).rev() {
+        if v.is_invalid() || v.is_leaf() {
+            return None;
+        }
+        let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+        v = unsafe { entry.add(vpn(vaddr, i)).as_mut().unwrap() };
+    }
+    if v.is_valid() && v.is_leaf() {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+// Handle a store page fault (scause 15) at vaddr in root's address space,
+// which is tagged asid.
+// If the page is mapped copy-on-write, the faulting mapping gets a private
+// writable copy (or just becomes writable if it holds the page's only
+// reference) and true is returned so the store can be retried.
+// false means the fault is a real one.
+pub fn cow_fault(root: &mut Table, asid: u16, vaddr: usize) -> bool {
+    let v = match leaf_entry(root, vaddr) {
+        Some(v) => v,
+        None => return false,
+    };
+    let entry = v.get_entry();
+    if entry & EntryBits::Cow.val() == 0 {
+        return false;
+    }
+
+    let bits = (entry & 0x3ff & !EntryBits::Cow.val()) | EntryBits::Write.val();
+    let paddr = ((entry & !0x3ff) << 2) as usize;
+    // only a page whose one reference is this mapping's can be written in
+    // place, one outside the allocator (0 refs) may still be seen elsewhere
+    if entry & EntryBits::Ref.val() != 0 && page_refs(paddr) == 1 {
+        // last one sharing it, keep the page
+        v.set_entry((entry & !0x3ff) | bits);
+    } else {
+        let page = alloc(1);
+        if page.is_null() {
+            return false;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(paddr as *const u8, page, PAGE_SIZE);
+        }
+        // alloc's reference becomes the mapping's, the old one is dropped
+        put_entry(v);
+        v.set_entry((page as i64 >> 2) | bits | EntryBits::Ref.val());
+    }
+
+    // throw away the stale read-only translation
+    sfence_page(vaddr, asid);
+    true
+}
+
+// ==========================================================================================================
+// Demand paging
+// ==========================================================================================================
+
+// a reserved virtual range, pages are only allocated once touched
+// node first, so a tree node is the region
+#[repr(C)]
+pub struct LazyRegion {
+    node: RangeNode,
+    bits: i64,
+}
+
+impl SlabObject for LazyRegion {}
+
+static mut LAZY_REGIONS: SlabCache<LazyRegion> = SlabCache::new();
+
+// the reserved ranges of one address space, kept next to its root Table
+pub struct LazyRegions {
+    tree: RangeTree,
+    // the address space's ASID, for flushing
+    asid: u16,
+}
+
+impl LazyRegions {
+    pub const fn new(asid: u16) -> Self {
+        LazyRegions {
+            tree: RangeTree::new(),
+            asid,
+        }
+    }
+
+    // Reserve [vaddr, vaddr + len) to be backed by zeroed pages with the
+    // given bits as they're first touched, no memory is used until then.
+    // returns false if the range overlaps another reservation
+    pub fn reserve(&mut self, vaddr: usize, len: usize, bits: i64) -> bool {
+        assert!(vaddr % PAGE_SIZE == 0 && len % PAGE_SIZE == 0 && len > 0);
+        assert!(bits & 0xe != 0);
+        unsafe {
+            if self.tree.find_overlap(vaddr, vaddr + len).is_some() {
+                return false;
+            }
+            let region = LAZY_REGIONS.alloc();
+            if region.is_null() {
+                return false;
+            }
+            (*region).node = RangeNode::new(vaddr, vaddr + len);
+            (*region).bits = bits;
+            self.tree.insert(region as *mut RangeNode)
+        }
+    }
+
+    // Drop the reservation starting at vaddr, giving back every page
+    // faulted into it. returns false if there is no such reservation, or
+    // it can't be unmapped since a superpage sticks out of it
+    pub fn release(&mut self, root: &mut Table, vaddr: usize) -> bool {
+        let node = match self.tree.find(vaddr) {
+            Some(node) if unsafe { (*node).start } == vaddr => node,
+            _ => return false,
+        };
+        unsafe {
+            if !unmap_range(root, self.asid, (*node).start, (*node).end - (*node).start, true) {
+                return false;
+            }
+            self.tree.remove(node);
+            LAZY_REGIONS.free(node as *mut LazyRegion);
+        }
+        true
+    }
+
+    // Handle a page fault at vaddr in root's address space.
+    // If vaddr is reserved and not mapped yet, a zeroed page is mapped in
+    // and true is returned so the access can be retried.
+    // false means the fault is a real one.
+    pub fn fault(&self, root: &mut Table, vaddr: usize) -> bool {
+        let region = match self.tree.find(vaddr) {
+            Some(node) => node as *mut LazyRegion,
+            None => return false,
+        };
+        let vpage = vaddr & !(PAGE_SIZE - 1);
+        if leaf_span(root, vpage).is_some() {
+            // already there (maybe inside a superpage), so it's a
+            // protection fault
+            return false;
+        }
+        let page = zalloc(1);
+        if page.is_null() {
+            return false;
+        }
+        map(root, vpage, page as usize, unsafe { (*region).bits }, 0);
+        // the mapping holds its own reference now
+        dealloc(page);
+        sfence_page(vpage, self.asid);
+        true
+    }
+
+    // number of reserved ranges
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+}
+
+// ==========================================================================================================
+// Page table dump
+// ==========================================================================================================
+
+// a run of contiguous leaf mappings with the same bits
+// (vaddr and paddr both advance together), printed as one line
+struct MapRun {
+    vaddr: usize,
+    paddr: usize,
+    len: usize,
+    bits: i64,
+}
+
+impl MapRun {
+    fn print(&self) {
+        // V is always set on a leaf so it isn't shown
+        let flag = |bit: EntryBits, c: char| {
+            if self.bits & bit.val() != 0 {
+                c
+            } else {
+                '-'
+            }
+        };
+        println!(
+            "0x{:016x} -> 0x{:016x} {:>10} bytes {}{}{}{}{}{}{}{}",
+            self.vaddr,
+            self.paddr,
+            self.len,
+            flag(EntryBits::Read, 'r'),
+            flag(EntryBits::Write, 'w'),
+            flag(EntryBits::Execute, 'x'),
+            flag(EntryBits::User, 'u'),
+            flag(EntryBits::Global, 'g'),
+            flag(EntryBits::Access, 'a'),
+            flag(EntryBits::Dirty, 'd'),
+            flag(EntryBits::Cow, 'c'),
+        );
+    }
+}
+
+// walk one table recursively, collecting leaves into runs
+// vbase: virt addr covered by entry 0 of this table
+fn walk_table(table: &Table, level: usize, vbase: usize, run: &mut Option<MapRun>) {
+    // each entry at this level covers 4 KiB, 2 MiB, 1 GiB, or 512 GiB
+    let size = 1usize << (12 + level * 9);
+    for (i, entry) in table.entries.iter().enumerate() {
+        if entry.is_invalid() {
+            continue;
+        }
+        let vaddr = sign_extend(vbase + i * size);
+        let paddr = ((entry.get_entry() & !0x3ff) << 2) as usize;
+
+        if entry.is_leaf() {
+            let bits = entry.get_entry() & 0x3ff;
+            let extends = match run {
+                Some(r) => {
+                    r.vaddr + r.len == vaddr && r.paddr + r.len == paddr && r.bits == bits
+                }
+                None => false,
+            };
+            if extends {
+                run.as_mut().unwrap().len += size;
+            } else {
+                if let Some(r) = run {
+                    r.print();
+                }
+                *run = Some(MapRun {
+                    vaddr,
+                    paddr,
+                    len: size,
+                    bits,
+                });
+            }
+        } else if level > 0 {
+            let next = unsafe { (paddr as *const Table).as_ref().unwrap() };
+            walk_table(next, level - 1, vbase + i * size, run);
+        }
+    }
+}
+
+/// Print every mapping in a page table, merging contiguous leaves that
+/// share the same bits into one line: vaddr -> paddr, length, and the
+/// R/W/X/U/G/A/D bits.
+pub fn print_table(root: &Table) {
+    println!();
+    println!("PAGE TABLE {:p}", root);
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    let mut run = None;
+    walk_table(root, LEVELS - 1, 0, &mut run);
+    if let Some(r) = run {
+        r.print();
+    }
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    println!();
+}
+
+// get the root table a SATP value points at,
+// None if translation is off (bare mode) or not the mode we're built for
+pub fn satp_to_table(satp: usize) -> Option<&'static Table> {
+    let mode = satp >> 60;
+    if mode != SATP_MODE {
+        return None;
+    }
+    let ppn = satp & ((1 << 44) - 1);
+    unsafe { ((ppn << 12) as *const Table).as_ref() }
+}
+
+/// Decode a SATP value and dump the page table it points at
+pub fn print_satp(satp: usize) {
+    let asid = (satp >> 44) & 0xffff;
+    match satp_to_table(satp) {
+        Some(root) => {
+            println!("SATP 0x{:x}: {}, ASID {}", satp, MODE_NAME, asid);
+            print_table(root);
+        }
+        None => println!("SATP 0x{:x}: translation off or unsupported mode", satp),
+    }
+}
+
+// ==========================================================================================================
+// Kernel stacks
+// ==========================================================================================================
+
+// [GUARD] <-- vaddr, never mapped
+// [STACK PAGE 1]
+// ...
+// [STACK PAGE pages] <-- top, the initial sp
+// A stack that runs past its bottom faults on the guard page instead of
+// silently writing over whatever sits below it.
+
+// the guard page of a mapped stack, node first so a tree node is the guard
+#[repr(C)]
+struct StackGuard {
+    node: RangeNode,
+    top: usize,
+}
+
+impl SlabObject for StackGuard {}
+
+static mut STACK_GUARDS: RangeTree = RangeTree::new();
+static mut STACK_GUARD_CACHE: SlabCache<StackGuard> = SlabCache::new();
+
+// Map a kernel stack of pages pages into root above an unmapped guard
+// page at vaddr. returns the top of the stack, or None if out of memory
+// or anything in the guard page or the stack is already mapped
+pub fn map_stack(root: &mut Table, vaddr: usize, pages: usize) -> Option<usize> {
+    assert!(vaddr % PAGE_SIZE == 0 && pages > 0);
+    let top = vaddr + (pages + 1) * PAGE_SIZE;
+    // map() would write over whatever is there, superpages included
+    let mut v = vaddr;
+    while v < top {
+        if leaf_span(root, v).is_some() {
+            return None;
+        }
+        v += PAGE_SIZE;
+    }
+    unsafe {
+        if STACK_GUARDS.find_overlap(vaddr, top).is_some() {
+            return None;
+        }
+        let guard = STACK_GUARD_CACHE.alloc();
+        if guard.is_null() {
+            return None;
+        }
+        let stack = alloc(pages);
+        if stack.is_null() {
+            STACK_GUARD_CACHE.free(guard);
+            return None;
+        }
+        for i in 0..pages {
+            map(
+                root,
+                vaddr + (i + 1) * PAGE_SIZE,
+                stack as usize + i * PAGE_SIZE,
+                EntryBits::RW.val(),
+                0,
+            );
+        }
+        // the mappings hold the stack from here on
+        dealloc(stack);
+
+        // the guard covers the whole range so unmap_stack can find it by
+        // the stack's vaddr, but only its first page faults
+        (*guard).node = RangeNode::new(vaddr, top);
+        (*guard).top = top;
+        STACK_GUARDS.insert(guard as *mut RangeNode);
+    }
+    Some(top)
+}
+
+// Unmap a stack mapped at vaddr by map_stack and free its pages
+// returns false if no stack was mapped there, or it can't be unmapped
+// since a superpage was mapped over its ends
+pub fn unmap_stack(root: &mut Table, vaddr: usize) -> bool {
+    unsafe {
+        let node = match STACK_GUARDS.find(vaddr) {
+            Some(node) if (*node).start == vaddr => node,
+            _ => return false,
+        };
+        let guard = node as *mut StackGuard;
+        // kernel stacks live in the kernel's address space, ASID 0
+        if !unmap_range(root, 0, vaddr + PAGE_SIZE, (*guard).top - vaddr - PAGE_SIZE, true) {
+            return false;
+        }
+        STACK_GUARDS.remove(node);
+        STACK_GUARD_CACHE.free(guard);
+    }
+    true
+}
+
+// For the trap handler on a page fault: if vaddr is in the guard page of
+// a kernel stack, report the overflow and return true so the handler can
+// stop instead of carrying on with a smashed stack.
+// The handler should be running on its own trap stack by then.
+pub fn check_stack_overflow(vaddr: usize) -> bool {
+    unsafe {
+        match STACK_GUARDS.find(vaddr) {
+            Some(node) if vaddr < (*node).start + PAGE_SIZE => {
+                println!(
+                    "kernel stack overflow at 0x{:x} (stack 0x{:x} -> 0x{:x})",
+                    vaddr,
+                    (*node).start + PAGE_SIZE,
+                    (*(node as *mut StackGuard)).top
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::collections::list::{Link, List};
+use crate::page::{self, Tag, PAGE_SIZE};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+
+// SLAB ALLOCATOR
+// A cache hands out fixed-size objects of one type, carved out of slabs
+// of whole pages, so objects that come and go all the time don't
+// fragment kmem.
+//
+// [SLAB]
+// +--> [Slab | pad | slot 0 | slot 1 | ... | slot n-1]
+//
+// Slab: Link onto the cache's partial or full list, the cache it belongs
+//       to, the first free slot, and how many slots are in use
+// A free slot holds a pointer to the next free slot of its slab.
+//
+// Slabs with a free slot are on the partial list, the rest on the full
+// list. A slab that empties out goes back to the page allocator unless
+// it's the only one left with free slots.
+// A slab is its own page allocation, so free finds an object's slab from
+// the page allocator, and a cache can't be moved once it has slabs.
+
+// aim for at least this many objects per slab
+const MIN_OBJS: usize = 8;
+
+// constructor/destructor hooks, run on an object as a cache hands it out
+// and as it's given back
+pub trait SlabObject: Sized {
+    // build the object in place, by default it's zeroed
+    fn construct(obj: *mut Self) {
+        unsafe {
+            core::ptr::write_bytes(obj, 0, 1);
+        }
+    }
+
+    // clean up before the slot is reused
+    fn destruct(_obj: *mut Self) {}
+}
+
+// link first, so a slab's Link is the slab
+#[repr(C)]
+struct Slab {
+    link: Link,
+    cache: usize,
+    free: *mut u8,
+    used: usize,
+}
+
+pub struct SlabCache<T> {
+    // slabs with at least one free slot
+    partial: List,
+    full: List,
+    // bytes per slot, enough to hold a T or a free pointer
+    slot_size: usize,
+    // offset of slot 0 from the start of a slab
+    first_slot: usize,
+    pages: usize,
+    slots: usize,
+    in_use: usize,
+    _type: PhantomData<T>,
+}
+
+const fn align_to(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
+
+// no branches, so it works in a const fn
+const fn max(a: usize, b: usize) -> usize {
+    a * (a >= b) as usize + b * (a < b) as usize
+}
+
+impl<T> SlabCache<T> {
+    // const so caches can live in statics
+    pub const fn new() -> Self {
+        let align = max(align_of::<T>(), align_of::<usize>());
+        let slot_size = align_to(max(size_of::<T>(), size_of::<usize>()), align);
+        let first_slot = align_to(size_of::<Slab>(), align);
+        let pages = align_to(first_slot + MIN_OBJS * slot_size, PAGE_SIZE) / PAGE_SIZE;
+        SlabCache {
+            partial: List::new(),
+            full: List::new(),
+            slot_size,
+            first_slot,
+            pages,
+            slots: (pages * PAGE_SIZE - first_slot) / slot_size,
+            in_use: 0,
+            _type: PhantomData,
+        }
+    }
+
+    // objects currently handed out
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    // objects that fit in one slab
+    pub fn slots_per_slab(&self) -> usize {
+        self.slots
+    }
+
+    // slabs currently held by the cache
+    pub fn slabs(&self) -> usize {
+        self.partial.len() + self.full.len()
+    }
+}
+
+impl<T: SlabObject> SlabCache<T> {
+    // allocate an object, returns null if out of pages
+    pub fn alloc(&mut self) -> *mut T {
+        assert!(align_of::<T>() <= PAGE_SIZE);
+        unsafe {
+            let link = match self.partial.front() {
+                Some(link) => link,
+                None => {
+                    let link = self.grow();
+                    if link.is_null() {
+                        return null_mut();
+                    }
+                    link
+                }
+            };
+            let slab = link as *mut Slab;
+
+            let slot = (*slab).free;
+            (*slab).free = *(slot as *mut *mut u8);
+            (*slab).used += 1;
+            if (*slab).free.is_null() {
+                self.partial.remove(link);
+                self.full.push_front(link);
+            }
+            self.in_use += 1;
+
+            let obj = slot as *mut T;
+            T::construct(obj);
+            obj
+        }
+    }
+
+    // give an object back to the cache it came from
+    pub fn free(&mut self, obj: *mut T) {
+        if obj.is_null() {
+            return;
+        }
+        unsafe {
+            let slab = self.slab_of(obj as usize);
+            assert!(!slab.is_null(), "Not an object from this cache!");
+            let offset = obj as usize - slab as usize;
+            assert!(
+                offset >= self.first_slot
+                    && (offset - self.first_slot) % self.slot_size == 0
+                    && (offset - self.first_slot) / self.slot_size < self.slots,
+                "Not the start of an object!"
+            );
+            T::destruct(obj);
+
+            let link = slab as *mut Link;
+            if (*slab).free.is_null() {
+                // was full, has room again
+                self.full.remove(link);
+                self.partial.push_front(link);
+            }
+            let slot = obj as *mut u8;
+            *(slot as *mut *mut u8) = (*slab).free;
+            (*slab).free = slot;
+            (*slab).used -= 1;
+            self.in_use -= 1;
+
+            if (*slab).used == 0 && self.partial.len() > 1 {
+                self.partial.remove(link);
+                page::dealloc(slab as *mut u8);
+            }
+        }
+    }
+
+    // get a new slab from the page allocator, thread its free list,
+    // and put it on the partial list
+    unsafe fn grow(&mut self) -> *mut Link {
+        let base = page::alloc_tagged(self.pages, Tag::Kernel);
+        if base.is_null() {
+            return null_mut();
+        }
+        let slab = base as *mut Slab;
+        (*slab).link = Link::new();
+        (*slab).cache = self as *const Self as usize;
+        (*slab).used = 0;
+        (*slab).free = null_mut();
+        // push slots in reverse so they're handed out in address order
+        for i in (0..self.slots).rev() {
+            let slot = base.add(self.first_slot + i * self.slot_size);
+            *(slot as *mut *mut u8) = (*slab).free;
+            (*slab).free = slot;
+        }
+        let link = slab as *mut Link;
+        self.partial.push_front(link);
+        link
+    }
+
+    // find the slab holding an address, null if none of ours does
+    unsafe fn slab_of(&self, addr: usize) -> *mut Slab {
+        match page::alloc_base(addr) {
+            Some(base) if (*(base as *mut Slab)).cache == self as *const Self as usize => {
+                base as *mut Slab
+            }
+            _ => null_mut(),
+        }
+    }
+}
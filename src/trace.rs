@@ -0,0 +1,137 @@
+use crate::collections::ring_buffer::RingBuffer;
+use crate::tty::{mtime, MTIME_FREQ};
+use crate::uart;
+
+// TRACE EVENTS
+// Built with the trace feature, trace! points record fixed-size binary
+// events into a ring buffer per hart, keeping the newest TRACE_LEN.
+// dump() writes them out the log UART for a host tool to decode into a
+// timeline. Without the feature trace! compiles to nothing.
+//
+// DUMP FORMAT (all fields little endian)
+// [HEADER]
+// +--> magic "EOSTRACE" | version u16 (1) | record size u16 (24)
+//      | harts u16 | reserved u16 | timestamp Hz u64
+// [HART SECTION] x harts
+// +--> hart u16 | reserved u16 | records u32 | dropped u32
+//      (records overwritten before they were dumped)
+// +--> [RECORD] x records, oldest first
+//      time u64 (mtime ticks) | a0 u64 | a1 u32 | event u16 | reserved u16
+//
+// Events and their arguments:
+// 1 page_alloc    a0 = physical address, a1 = pages
+// 2 page_free     a0 = physical address, a1 = pages
+// 3 sched_switch  reserved, there's no scheduler yet
+// 4 irq_enter     reserved, there's no interrupt handling yet
+// 5 irq_exit      reserved
+// 6 syscall_enter reserved, there are no syscalls yet
+// 7 syscall_exit  reserved
+
+// harts QEMU's virt machine is started with (CPUS in the Makefile)
+const MAX_HARTS: usize = 4;
+// records kept per hart
+const TRACE_LEN: usize = 256;
+const VERSION: u16 = 1;
+const RECORD_SIZE: u16 = 24;
+
+#[repr(u16)]
+#[derive(Copy, Clone)]
+pub enum Event {
+    PageAlloc = 1,
+    PageFree = 2,
+    SchedSwitch = 3,
+    IrqEnter = 4,
+    IrqExit = 5,
+    SyscallEnter = 6,
+    SyscallExit = 7,
+}
+
+impl Event {
+    pub fn val(self) -> u16 {
+        self as u16
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Record {
+    time: u64,
+    a0: u64,
+    a1: u32,
+    event: u16,
+}
+
+// made on the first event a hart records
+static mut BUFS: [Option<RingBuffer<Record, TRACE_LEN>>; MAX_HARTS] = [None, None, None, None];
+static mut DROPPED: [u32; MAX_HARTS] = [0; MAX_HARTS];
+
+fn hart_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("csrr $0, mhartid" : "=r"(id) ::: "volatile");
+    }
+    id
+}
+
+// record an event on this hart, use trace! so it's compiled out
+// without the trace feature
+pub fn record(event: Event, a0: u64, a1: u32) {
+    let hart = hart_id();
+    if hart >= MAX_HARTS {
+        return;
+    }
+    let record = Record {
+        time: mtime(),
+        a0,
+        a1,
+        event: event.val(),
+    };
+    unsafe {
+        if BUFS[hart].is_none() {
+            BUFS[hart] = Some(RingBuffer::new());
+        }
+        let buf = BUFS[hart].as_mut().unwrap();
+        if buf.is_full() {
+            DROPPED[hart] += 1;
+        }
+        buf.force_push(record);
+    }
+}
+
+fn put_bytes(uart: &mut uart::Uart, bytes: &[u8]) {
+    for &b in bytes {
+        uart.put(b);
+    }
+}
+
+/// Write every recorded event out the log UART in the format described
+/// at the top of this file, emptying the buffers.
+pub fn dump() {
+    let mut uart = uart::log_port();
+    put_bytes(&mut uart, b"EOSTRACE");
+    put_bytes(&mut uart, &VERSION.to_le_bytes());
+    put_bytes(&mut uart, &RECORD_SIZE.to_le_bytes());
+    put_bytes(&mut uart, &(MAX_HARTS as u16).to_le_bytes());
+    put_bytes(&mut uart, &0u16.to_le_bytes());
+    put_bytes(&mut uart, &MTIME_FREQ.to_le_bytes());
+
+    for hart in 0..MAX_HARTS {
+        unsafe {
+            let records = BUFS[hart].as_ref().map_or(0, |buf| buf.len());
+            put_bytes(&mut uart, &(hart as u16).to_le_bytes());
+            put_bytes(&mut uart, &0u16.to_le_bytes());
+            put_bytes(&mut uart, &(records as u32).to_le_bytes());
+            put_bytes(&mut uart, &DROPPED[hart].to_le_bytes());
+            DROPPED[hart] = 0;
+
+            if let Some(buf) = BUFS[hart].as_mut() {
+                while let Some(r) = buf.pop_front() {
+                    put_bytes(&mut uart, &r.time.to_le_bytes());
+                    put_bytes(&mut uart, &r.a0.to_le_bytes());
+                    put_bytes(&mut uart, &r.a1.to_le_bytes());
+                    put_bytes(&mut uart, &r.event.to_le_bytes());
+                    put_bytes(&mut uart, &0u16.to_le_bytes());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use crate::collections::ring_buffer::RingBuffer;
+use crate::uart::{Uart, UartStats};
+use core::fmt::{Error, Write};
+
+// TTY LAYER
+// [Uart] --get()--> [line discipline] --read()--> caller
+//                         |
+//                         +--> echo back out through the Uart
+//
+// In canonical mode input is collected and edited a line at a time and
+// only handed to read() once the line is finished. In raw mode bytes are
+// passed straight through, with VMIN/VTIME deciding when read() returns.
+
+// control characters
+const BACKSPACE: u8 = b'\x08';
+const DELETE: u8 = b'\x7f';
+const NEWLINE: u8 = b'\x0a';
+const CARR_RET: u8 = b'\x0d';
+const CTRL_D: u8 = b'\x04';
+const CTRL_U: u8 = b'\x15';
+
+// input mode flags (iflag)
+pub const ICRNL: u32 = 1 << 0; // translate carriage return to newline
+pub const IGNBRK: u32 = 1 << 1; // ignore breaks
+pub const BRKINT: u32 = 1 << 2; // a break flushes input and raises an interrupt
+pub const IGNPAR: u32 = 1 << 3; // drop bytes with parity or framing errors
+
+// output mode flags (oflag)
+pub const ONLCR: u32 = 1 << 0; // translate newline to carriage return + newline
+
+// local mode flags (lflag)
+pub const ICANON: u32 = 1 << 0; // canonical (line at a time) input
+pub const ECHO: u32 = 1 << 1; // echo input characters
+pub const ECHOE: u32 = 1 << 2; // erase echoes as backspace, space, backspace
+pub const ECHOK: u32 = 1 << 3; // kill erases the echoed line
+
+// indices into Termios::cc
+pub const VERASE: usize = 0;
+pub const VKILL: usize = 1;
+pub const VEOF: usize = 2;
+pub const VMIN: usize = 3;
+pub const VTIME: usize = 4;
+pub const NCCS: usize = 5;
+
+// longest line canonical mode will buffer, including the newline
+const LINE_MAX: usize = 256;
+
+// machine timer counter in the CLINT, used for VTIME timeouts
+// QEMU's virt machine ticks it at 10 MHz
+const MTIME: usize = 0x0200_bff8;
+pub(crate) const MTIME_FREQ: u64 = 10_000_000;
+
+pub(crate) fn mtime() -> u64 {
+    unsafe { (MTIME as *const u64).read_volatile() }
+}
+
+#[derive(Copy, Clone)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub lflag: u32,
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    // cooked mode: line editing with echo
+    pub fn new() -> Self {
+        let mut cc = [0; NCCS];
+        cc[VERASE] = DELETE;
+        cc[VKILL] = CTRL_U;
+        cc[VEOF] = CTRL_D;
+        cc[VMIN] = 1;
+        cc[VTIME] = 0;
+
+        Termios {
+            iflag: ICRNL | BRKINT,
+            oflag: ONLCR,
+            lflag: ICANON | ECHO | ECHOE | ECHOK,
+            cc,
+        }
+    }
+
+    // raw mode: no line editing, translation, or echo
+    // and read() returns as soon as a single byte is available
+    pub fn make_raw(&mut self) {
+        self.iflag &= !(ICRNL | IGNBRK | BRKINT);
+        self.oflag &= !ONLCR;
+        self.lflag &= !(ICANON | ECHO | ECHOE | ECHOK);
+        self.cc[VMIN] = 1;
+        self.cc[VTIME] = 0;
+    }
+}
+
+pub struct Tty {
+    uart: Uart,
+    pub termios: Termios,
+    // the line being edited (canonical mode)
+    // erase takes bytes off the back, read() drains it from the front
+    line: RingBuffer<u8, LINE_MAX>,
+    // set once the line is terminated and can be read out
+    line_done: bool,
+    // a break arrived with BRKINT set, waiting for take_interrupt()
+    interrupted: bool,
+}
+
+// writes go through output processing so println! style
+// formatting works on a TTY as well
+impl Write for Tty {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Tty {
+    pub fn new(base_addr: usize) -> Self {
+        Tty {
+            uart: Uart::new(base_addr),
+            termios: Termios::new(),
+            line: RingBuffer::new(),
+            line_done: false,
+            interrupted: false,
+        }
+    }
+
+    // Returns true (once) if a break came in with BRKINT set. This is
+    // where SIGINT will be raised from once there are signals.
+    pub fn take_interrupt(&mut self) -> bool {
+        let interrupted = self.interrupted;
+        self.interrupted = false;
+        interrupted
+    }
+
+    // error counters of the uart behind this tty
+    pub fn uart_stats(&self) -> UartStats {
+        self.uart.stats()
+    }
+
+    // Get the next input byte after line error handling.
+    // None if nothing is waiting or the byte was dropped.
+    fn receive(&mut self) -> Option<u8> {
+        let (c, lsr) = self.uart.get_with_status()?;
+        let iflag = self.termios.iflag;
+
+        if lsr.break_interrupt() {
+            if iflag & IGNBRK != 0 {
+                return None;
+            }
+            if iflag & BRKINT != 0 {
+                // like ^C: throw away what's been typed and flag it
+                self.line.clear();
+                self.line_done = false;
+                self.interrupted = true;
+                return None;
+            }
+            // otherwise a break reads as a NUL byte
+            return Some(0);
+        }
+        if (lsr.parity_error() || lsr.framing_error()) && iflag & IGNPAR != 0 {
+            return None;
+        }
+        Some(c)
+    }
+
+    // read input into buf, returning the number of bytes read
+    // in canonical mode a return of 0 means end of file (VEOF on an empty line)
+    // a break with BRKINT set cuts a read short, take_interrupt() tells
+    // that apart from the other reasons a read ends
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        if self.termios.lflag & ICANON != 0 {
+            self.read_canonical(buf)
+        } else {
+            self.read_raw(buf)
+        }
+    }
+
+    // write bytes out, applying output processing
+    pub fn write(&mut self, buf: &[u8]) {
+        for &c in buf {
+            self.output(c);
+        }
+    }
+
+    fn read_canonical(&mut self, buf: &mut [u8]) -> usize {
+        // poll until a full line has been entered
+        while !self.line_done {
+            if self.interrupted {
+                return 0;
+            }
+            if let Some(c) = self.receive() {
+                self.input_canonical(c);
+            }
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match self.line.pop_front() {
+                Some(c) => {
+                    buf[n] = c;
+                    n += 1;
+                }
+                None => break,
+            }
+        }
+
+        // whole line consumed, start editing a new one
+        if self.line.is_empty() {
+            self.line_done = false;
+        }
+        n
+    }
+
+    fn input_canonical(&mut self, c: u8) {
+        let c = self.translate(c);
+        let cc = self.termios.cc;
+        let lflag = self.termios.lflag;
+
+        if c == cc[VERASE] || c == BACKSPACE {
+            if self.line.pop_back().is_some() {
+                self.echo_erase();
+            }
+        } else if c == cc[VKILL] {
+            if lflag & ECHOK != 0 {
+                while self.line.pop_back().is_some() {
+                    self.echo_erase();
+                }
+            } else {
+                self.line.clear();
+                self.echo(c);
+            }
+        } else if c == cc[VEOF] {
+            // EOF isn't stored, it just ends the line as is
+            self.line_done = true;
+        } else if c == NEWLINE {
+            let _ = self.line.push_back(c);
+            self.line_done = true;
+            self.echo(c);
+        } else if self.line.len() < LINE_MAX - 1 {
+            // always keep room for the terminating newline
+            let _ = self.line.push_back(c);
+            self.echo(c);
+        }
+    }
+
+    // VMIN/VTIME semantics:
+    // MIN = 0, TIME = 0: return whatever is available right away
+    // MIN > 0, TIME = 0: block until MIN bytes have arrived
+    // MIN = 0, TIME > 0: wait up to TIME for the first byte
+    // MIN > 0, TIME > 0: after the first byte, return when MIN bytes
+    //                    have arrived or TIME passes between two bytes
+    fn read_raw(&mut self, buf: &mut [u8]) -> usize {
+        let vmin = self.termios.cc[VMIN] as usize;
+        // VTIME is in tenths of a second
+        let vtime = self.termios.cc[VTIME] as u64 * (MTIME_FREQ / 10);
+        let mut n = 0;
+        let mut last = mtime();
+
+        loop {
+            // drain everything the uart has ready
+            while n < buf.len() {
+                match self.receive() {
+                    Some(c) => {
+                        let c = self.translate(c);
+                        buf[n] = c;
+                        n += 1;
+                        self.echo(c);
+                        last = mtime();
+                    }
+                    None => break,
+                }
+            }
+
+            if n == buf.len() || (vmin > 0 && n >= vmin) || self.interrupted {
+                break;
+            }
+            if vtime == 0 {
+                if vmin == 0 {
+                    break;
+                }
+            } else if vmin == 0 && n > 0 {
+                // the first byte is all a timed read waits for
+                break;
+            } else if (vmin == 0 || n > 0) && mtime() - last >= vtime {
+                break;
+            }
+        }
+        n
+    }
+
+    fn translate(&self, c: u8) -> u8 {
+        if c == CARR_RET && self.termios.iflag & ICRNL != 0 {
+            NEWLINE
+        } else {
+            c
+        }
+    }
+
+    fn echo(&mut self, c: u8) {
+        if self.termios.lflag & ECHO != 0 {
+            self.output(c);
+        }
+    }
+
+    // for erase we need to move back 1 char, then overwrite
+    // char at point with space, then move back again
+    fn echo_erase(&mut self) {
+        let lflag = self.termios.lflag;
+        if lflag & ECHO == 0 {
+            return;
+        }
+        if lflag & ECHOE != 0 {
+            self.uart.put(BACKSPACE);
+            self.uart.put(b' ');
+            self.uart.put(BACKSPACE);
+        } else {
+            self.output(self.termios.cc[VERASE]);
+        }
+    }
+
+    fn output(&mut self, c: u8) {
+        if c == NEWLINE && self.termios.oflag & ONLCR != 0 {
+            self.uart.put(CARR_RET);
+        }
+        self.uart.put(c);
+    }
+}
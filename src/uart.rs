@@ -1,8 +1,197 @@
 use core::convert::TryInto;
 use core::fmt::{Error, Write};
 
+// the ns16550a on QEMU's virt machine
+pub const CONSOLE_BASE: usize = 0x1000_0000;
+
+// UART that print!/println! send the kernel log to
+static mut LOG_PORT: usize = CONSOLE_BASE;
+
+// clock-frequency of the ns16550a node in QEMU virt's device tree
+pub const DEFAULT_CLOCK: u32 = 3_686_400;
+// QEMU's stdio serial expects 115200 8N1
+pub const DEFAULT_BAUD: u32 = 115_200;
+// how far set_baud may land from the requested rate, in thousandths
+// a serial link starts dropping bits somewhere past 2-3% off
+const BAUD_TOLERANCE: u64 = 25;
+
+// Modem Control Register (base + 4) bits
+const MCR_DTR: u8 = 1 << 0; // data terminal ready
+const MCR_RTS: u8 = 1 << 1; // request to send
+const MCR_OUT2: u8 = 1 << 3; // gates the interrupt line on PC-style boards
+const MCR_LOOP: u8 = 1 << 4; // loopback: TX is wired straight back to RX
+const MCR_AFE: u8 = 1 << 5; // auto flow control enable (16750 and later)
+
+// FIFO Control Register (base + 2, write only) bits
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+const FCR_FIFO64: u8 = 1 << 5; // 16750 only, and only writable with DLAB set
+
+// Interrupt Enable Register (base + 1) bits
+const IER_RX: u8 = 1 << 0;
+const IER_MODEM: u8 = 1 << 3;
+
+// how the driver paces data with the other end
+#[derive(Copy, Clone, PartialEq)]
+pub enum FlowControl {
+    // RTS and DTR held asserted, CTS ignored
+    None,
+    // software: put() waits for CTS before each byte
+    RtsCts,
+    // hardware handles RTS/CTS itself (AFE bit), needs a 16750-style UART
+    Auto,
+}
+
+// snapshot of the Modem Status Register (base + 6)
+// reading it clears the delta bits and any pending modem status interrupt
+#[derive(Copy, Clone)]
+pub struct ModemStatus(pub u8);
+
+impl ModemStatus {
+    pub fn cts_changed(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    pub fn dsr_changed(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    // ring indicator went from on to off
+    pub fn ring_ended(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    pub fn dcd_changed(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    pub fn cts(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    pub fn dsr(&self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
+
+    pub fn ring(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+
+    pub fn dcd(&self) -> bool {
+        self.0 & (1 << 7) != 0
+    }
+}
+
+// which FIFO the UART has
+#[derive(Copy, Clone, PartialEq)]
+pub enum FifoKind {
+    // 8250/16450 or a 16550 with the broken FIFO: a single holding register
+    None,
+    // 16550A
+    Fifo16,
+    // 16750
+    Fifo64,
+}
+
+impl FifoKind {
+    pub fn depth(self) -> usize {
+        match self {
+            FifoKind::None => 1,
+            FifoKind::Fifo16 => 16,
+            FifoKind::Fifo64 => 64,
+        }
+    }
+}
+
+// how full the RX FIFO gets before it raises a receive interrupt
+// (bytes left below the trigger still arrive via a CharTimeout interrupt)
+#[derive(Copy, Clone, PartialEq)]
+pub enum RxTrigger {
+    One,        // 1 byte on both
+    Quarter,    // 4 bytes on a 16550A, 16 on a 16750
+    Half,       // 8, 32
+    NearlyFull, // 14, 56
+}
+
+impl RxTrigger {
+    // FCR bits 7-6
+    fn bits(self) -> u8 {
+        match self {
+            RxTrigger::One => 0b00 << 6,
+            RxTrigger::Quarter => 0b01 << 6,
+            RxTrigger::Half => 0b10 << 6,
+            RxTrigger::NearlyFull => 0b11 << 6,
+        }
+    }
+}
+
+// Line Status Register (base + 5) as read alongside a received byte
+// the error bits describe the byte at the head of the RX FIFO
+#[derive(Copy, Clone)]
+pub struct LineStatus(pub u8);
+
+impl LineStatus {
+    pub fn data_ready(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    // a byte was lost because the RX FIFO was full
+    pub fn overrun(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    pub fn parity_error(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    // missing stop bit, usually a baud rate mismatch
+    pub fn framing_error(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    // line held low for longer than a whole character,
+    // the byte that comes with it is a 0
+    pub fn break_interrupt(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.0 & 0b1_1110 != 0
+    }
+}
+
+// counters kept per Uart handle
+#[derive(Copy, Clone, Default)]
+pub struct UartStats {
+    pub rx: usize,
+    pub tx: usize,
+    pub overrun: usize,
+    pub parity: usize,
+    pub framing: usize,
+    pub breaks: usize,
+}
+
+// interrupt sources, as reported by the Interrupt Identification Register
+#[derive(Copy, Clone, PartialEq)]
+pub enum Interrupt {
+    ModemStatus,
+    TransmitEmpty,
+    ReceiveData,
+    LineStatus,
+    CharTimeout,
+}
+
 pub struct Uart {
     base_addr: usize,
+    // input clock of the UART, used to work out baud divisors
+    clock_hz: u32,
+    // only honored by this handle, print! builds a fresh handle each time
+    // so it only gets flow control if it's done in hardware (Auto)
+    flow: FlowControl,
+    // what detect_fifo() found, assume no FIFO until then
+    fifo: FifoKind,
+    stats: UartStats,
 }
 
 // implement the Write trait for Uart struct, adding in the required
@@ -20,7 +209,19 @@ impl Write for Uart {
 
 impl Uart {
     pub fn new(base_addr: usize) -> Self {
-        Uart { base_addr }
+        Self::with_clock(base_addr, DEFAULT_CLOCK)
+    }
+
+    // for UARTs whose clock differs from QEMU's, e.g. the clock-frequency
+    // property read from the device tree
+    pub fn with_clock(base_addr: usize, clock_hz: u32) -> Self {
+        Uart {
+            base_addr,
+            clock_hz,
+            flow: FlowControl::None,
+            fifo: FifoKind::None,
+            stats: UartStats::default(),
+        }
     }
 
     // Initialize UART (Universal Async Receiver-Transmitter)
@@ -33,29 +234,190 @@ impl Uart {
             let lcr = (1 << 1) | (1 << 0);
             ptr.add(3).write_volatile(lcr);
 
-            // Set FCR (FIFO Control Register) at base + 2 to 0b1 to enable
-            // using a stack instead of a queue for UART read/write buffer
-            let fifo = 1 << 0;
-            ptr.add(2).write_volatile(fifo);
-
             // Enable receive buffer interrupts (IER at base + 1)
             // so we can trigger interrupts when data written to RBR
-            let ier = 1 << 0;
+            let ier = IER_RX;
+            ptr.add(1).write_volatile(ier);
+        }
+
+        // find out how deep the FIFO is and only interrupt once it's half full
+        // instead of on every byte
+        self.detect_fifo();
+        self.set_rx_trigger(RxTrigger::Half);
+
+        let _ = self.set_baud(DEFAULT_BAUD);
+        // assert DTR/RTS to tell the other end we're ready
+        self.set_flow_control(FlowControl::None);
+    }
+
+    // Probe which FIFO the UART has, leaving the FIFOs enabled and empty
+    pub fn detect_fifo(&mut self) -> FifoKind {
+        let ptr = self.base_addr as *mut u8;
+        let kind = unsafe {
+            // ask for the 64-byte FIFO, which a 16750 only accepts with DLAB set
+            let lcr = ptr.add(3).read_volatile();
+            ptr.add(3).write_volatile(lcr | (1 << 7));
+            ptr.add(2).write_volatile(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_FIFO64);
+            ptr.add(3).write_volatile(lcr);
+
+            // IIR bits 7-6 read 11 once working FIFOs are enabled,
+            // and bit 5 is set if the 64-byte FIFO took
+            let iir = ptr.add(2).read_volatile();
+            if iir & (0b11 << 6) != 0b11 << 6 {
+                FifoKind::None
+            } else if iir & (1 << 5) != 0 {
+                FifoKind::Fifo64
+            } else {
+                FifoKind::Fifo16
+            }
+        };
+        self.fifo = kind;
+        kind
+    }
+
+    pub fn fifo(&self) -> FifoKind {
+        self.fifo
+    }
+
+    // Set the RX trigger level (this also clears both FIFOs)
+    pub fn set_rx_trigger(&mut self, trigger: RxTrigger) {
+        let ptr = self.base_addr as *mut u8;
+        let mut fcr = FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | trigger.bits();
+        unsafe {
+            if self.fifo == FifoKind::Fifo64 {
+                // keep the 64-byte FIFO, which again needs DLAB set
+                fcr |= FCR_FIFO64;
+                let lcr = ptr.add(3).read_volatile();
+                ptr.add(3).write_volatile(lcr | (1 << 7));
+                ptr.add(2).write_volatile(fcr);
+                ptr.add(3).write_volatile(lcr);
+            } else {
+                ptr.add(2).write_volatile(fcr);
+            }
+        }
+    }
+
+    // Read everything waiting in the RX FIFO (up to buf.len() bytes) in one
+    // go, so a receive interrupt can empty the FIFO instead of taking a byte
+    // per interrupt. Returns the number of bytes read.
+    pub fn drain(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.get() {
+                Some(c) => {
+                    buf[n] = c;
+                    n += 1;
+                }
+                None => break,
+            }
+        }
+        n
+    }
+
+    pub fn set_flow_control(&mut self, flow: FlowControl) {
+        self.flow = flow;
+        let mut mcr = MCR_DTR | MCR_RTS | MCR_OUT2;
+        if flow == FlowControl::Auto {
+            mcr |= MCR_AFE;
+        }
+        unsafe {
+            (self.base_addr as *mut u8).add(4).write_volatile(mcr);
+        }
+    }
+
+    // drive RTS by hand, e.g. drop it while there's nowhere to put input
+    pub fn set_rts(&mut self, on: bool) {
+        self.set_mcr_bit(MCR_RTS, on);
+    }
+
+    pub fn set_dtr(&mut self, on: bool) {
+        self.set_mcr_bit(MCR_DTR, on);
+    }
+
+    fn set_mcr_bit(&mut self, bit: u8, on: bool) {
+        let ptr = self.base_addr as *mut u8;
+        unsafe {
+            let mcr = ptr.add(4).read_volatile();
+            let mcr = if on { mcr | bit } else { mcr & !bit };
+            ptr.add(4).write_volatile(mcr);
+        }
+    }
+
+    pub fn modem_status(&mut self) -> ModemStatus {
+        unsafe { ModemStatus((self.base_addr as *mut u8).add(6).read_volatile()) }
+    }
+
+    // raise an interrupt whenever CTS, DSR, RI, or DCD change
+    pub fn enable_modem_interrupts(&mut self, on: bool) {
+        let ptr = self.base_addr as *mut u8;
+        unsafe {
+            let ier = ptr.add(1).read_volatile();
+            let ier = if on { ier | IER_MODEM } else { ier & !IER_MODEM };
             ptr.add(1).write_volatile(ier);
+        }
+    }
+
+    // highest priority interrupt waiting, for the trap handler to dispatch on
+    // a ModemStatus interrupt is cleared by reading modem_status()
+    pub fn pending_interrupt(&mut self) -> Option<Interrupt> {
+        // Interrupt Identification Register shares base + 2 with the FCR,
+        // bit 0 clear means an interrupt is pending and bits 3-1 say which
+        let iir = unsafe { (self.base_addr as *mut u8).add(2).read_volatile() };
+        if iir & 1 != 0 {
+            return None;
+        }
+        match (iir >> 1) & 0b111 {
+            0b000 => Some(Interrupt::ModemStatus),
+            0b001 => Some(Interrupt::TransmitEmpty),
+            0b010 => Some(Interrupt::ReceiveData),
+            0b011 => Some(Interrupt::LineStatus),
+            0b110 => Some(Interrupt::CharTimeout),
+            _ => None,
+        }
+    }
+
+    // Set the baud rate, safe to call at runtime
+    // fails if no divisor of this UART's clock gets within
+    // BAUD_TOLERANCE of the rate
+    pub fn set_baud(&mut self, rate: u32) -> Result<(), ()> {
+        // signalling divisor determines how often the UART samples the line
+        // and is calculated by clock_rate / (signaling_rate (in BAUD) * 16),
+        // rounded to the nearest whole divisor
+        // e.g. QEMU's 3_686_400Hz clock at 115200 BAUD gives a divisor of 2
+        if rate == 0 {
+            return Err(());
+        }
+        let sixteenths = rate as u64 * 16;
+        let divisor = (self.clock_hz as u64 + sixteenths / 2) / sixteenths;
+        if divisor == 0 || divisor > u16::max_value() as u64 {
+            return Err(());
+        }
+        // the rate actually produced, which has to be close enough to the
+        // requested one for the other end to stay in sync
+        let achieved = self.clock_hz as u64 / (divisor * 16);
+        let error = if achieved > rate as u64 {
+            achieved - rate as u64
+        } else {
+            rate as u64 - achieved
+        };
+        if error * 1000 > rate as u64 * BAUD_TOLERANCE {
+            return Err(());
+        }
+        let divisor = divisor as u16;
 
-            // signalling divisor determines how often the CPU checks for signals
-            // and is calculated by ceil(clock_rate / signaling_rate (in BAUD) * 16)
-            // Using a value of 2400 for BAUD:
-            // 22_729_000Hz / 2400 x 1600 ~= 592 as divisor
+        // can only write 1 byte at a time so split divisor and write each
+        let divisor_lo: u8 = (divisor & 0xff).try_into().unwrap();
+        let divisor_hi: u8 = (divisor >> 8).try_into().unwrap();
 
-            // can only write 1 byte at a time so split divisor and write each
-            let divisor: u16 = 592;
-            let divisor_lo: u8 = (divisor & 0xff).try_into().unwrap();
-            let divisor_hi: u8 = (divisor >> 8).try_into().unwrap();
+        // let anything still being shifted out finish first
+        self.wait_tx_empty();
 
+        let ptr = self.base_addr as *mut u8;
+        unsafe {
             // need to flip Divisor Latch acccess Bit (DLAB) so that base + 0 and
             // base + 1 point to divisor latch least (DLL) and divisor latch most (DLM) bytes
             // instead of THR/RBR and IER
+            let lcr = ptr.add(3).read_volatile();
             let dlab = 1 << 7;
             ptr.add(3).write_volatile(lcr | dlab);
 
@@ -63,33 +425,145 @@ impl Uart {
             ptr.add(1).write_volatile(divisor_hi);
 
             // clear DLAB bit now so that we can access our RBR, THR, and IER again
-            ptr.add(3).write_volatile(lcr);
+            ptr.add(3).write_volatile(lcr & !dlab);
         }
+        Ok(())
+    }
+
+    // spin until everything written has been shifted out
+    pub fn wait_tx_empty(&mut self) {
+        let ptr = self.base_addr as *mut u8;
+        unsafe {
+            // bit 6 of the Line Status Register (TEMT) is set once the
+            // transmitter is empty
+            while ptr.add(5).read_volatile() & (1 << 6) == 0 {}
+        }
+    }
+
+    // Loopback self test: with MCR loopback set, every byte written comes
+    // straight back in, so send a pattern and check it all arrives intact.
+    // Nothing reaches the outside world while this runs.
+    pub fn selftest(&mut self) -> bool {
+        const PATTERN: [u8; 8] = [0x00, 0xff, 0x55, 0xaa, 0x0f, 0xf0, b'e', b's'];
+        // how long to poll for each byte before giving up
+        const TIMEOUT: usize = 100_000;
+
+        // don't loop back console output still on its way out
+        self.wait_tx_empty();
+
+        let ptr = self.base_addr as *mut u8;
+        let (mcr, ier) = unsafe { (ptr.add(4).read_volatile(), ptr.add(1).read_volatile()) };
+        unsafe {
+            // no interrupts for the looped back bytes
+            ptr.add(1).write_volatile(0);
+            ptr.add(4).write_volatile(mcr | MCR_LOOP);
+        }
+
+        // throw away anything that was already waiting
+        while self.get().is_some() {}
+
+        let mut ok = true;
+        'pattern: for &expected in PATTERN.iter() {
+            self.put(expected);
+            for _ in 0..TIMEOUT {
+                if let Some(c) = self.get() {
+                    if c != expected {
+                        ok = false;
+                        break 'pattern;
+                    }
+                    continue 'pattern;
+                }
+            }
+            // byte never came back
+            ok = false;
+            break;
+        }
+
+        unsafe {
+            ptr.add(4).write_volatile(mcr);
+            ptr.add(1).write_volatile(ier);
+        }
+        ok
     }
 
     pub fn get(&mut self) -> Option<u8> {
+        self.get_with_status().map(|(c, _)| c)
+    }
+
+    // Read a byte along with the line status describing it,
+    // counting any errors in this handle's stats
+    pub fn get_with_status(&mut self) -> Option<(u8, LineStatus)> {
         // *mut is a raw mutable pointer, meant to be shared and modified so long
         // as it's not changed to None
         let ptr = self.base_addr as *mut u8;
-        unsafe {
-            // Bit 0 of Line Status Register is the Data Ready (DR) register, which
-            // indicates if there is data to be read from RBR
-            if ptr.add(5).read_volatile() & 1 == 0 {
-                // No data to be read, return nothing
-                None
-            } else {
-                // bit must be 1, data can be received
-                // Use Some to indicate a return that can be
-                // evaluated for different return types
-                Some(ptr.add(0).read_volatile())
+        // reading LSR clears its error bits, so it's read exactly once per byte
+        let lsr = LineStatus(unsafe { ptr.add(5).read_volatile() });
+
+        // Bit 0 of Line Status Register is the Data Ready (DR) register, which
+        // indicates if there is data to be read from RBR
+        if !lsr.data_ready() {
+            // an overrun can be flagged even with nothing left to read
+            if lsr.overrun() {
+                self.stats.overrun += 1;
+            }
+            // No data to be read, return nothing
+            return None;
+        }
+
+        // bit must be 1, data can be received
+        // Use Some to indicate a return that can be
+        // evaluated for different return types
+        let c = unsafe { ptr.add(0).read_volatile() };
+        self.stats.rx += 1;
+        if lsr.overrun() {
+            self.stats.overrun += 1;
+        }
+        if lsr.break_interrupt() {
+            // a break also shows up as a framing error, only count it once
+            self.stats.breaks += 1;
+        } else {
+            if lsr.parity_error() {
+                self.stats.parity += 1;
+            }
+            if lsr.framing_error() {
+                self.stats.framing += 1;
             }
         }
+        Some((c, lsr))
+    }
+
+    pub fn stats(&self) -> UartStats {
+        self.stats
     }
 
     pub fn put(&mut self, c: u8) {
+        // hold off until the other end says it can take more
+        // (reading MSR here also clears its delta bits)
+        if self.flow == FlowControl::RtsCts {
+            while !self.modem_status().cts() {}
+        }
         let ptr = self.base_addr as *mut u8;
         unsafe {
             ptr.add(0).write_volatile(c);
         }
+        self.stats.tx += 1;
+    }
+}
+
+// Run the loopback self test on the console UART
+pub fn selftest() -> bool {
+    Uart::new(CONSOLE_BASE).selftest()
+}
+
+// handle for the UART the kernel log currently goes to
+pub fn log_port() -> Uart {
+    Uart::new(unsafe { LOG_PORT })
+}
+
+// Send the kernel log to another UART, e.g. to keep it apart from the
+// console a user or gdbstub is using. The port must already be init()ed.
+pub fn set_log_port(base_addr: usize) {
+    unsafe {
+        LOG_PORT = base_addr;
     }
 }